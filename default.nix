@@ -13,6 +13,8 @@ stdenv.mkDerivation {
   nativeBuildInputs = [
     cargo rustc sqlite-3-24
     openssl pkgconfig
+    # Runtime: `convert` makes avatar thumbnails and converts Matrix avatars
+    imagemagick
     # Dev depends
     rustfmt patchelf rustracer
   ];
@@ -6,6 +6,11 @@ cargo build --release
 cp -f ./target/release/batch ./target/holy-web
 patchelf --set-interpreter /lib64/ld-linux-x86-64.so.2 ./target/holy-web
 
+# Avatar thumbnails are made by ImageMagick on the server
+ssh holy@tg 'command -v convert >/dev/null' ||
+    echo "warning: ImageMagick convert is missing on tg," \
+         "avatar thumbnails won't be made" >&2
+
 scp ./scripts/init.sql holy@tg:~/web
 scp ./target/holy-web holy@tg:~/web/holy-web.new
 ssh holy@tg 'mv ~/web/holy-web{.new,}'
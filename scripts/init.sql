@@ -61,6 +61,13 @@ CREATE TABLE IF NOT EXISTS kv (
 );
 
 
+-- Chats hidden from the chat directory
+CREATE TABLE IF NOT EXISTS chats_unlisted (
+    id         NUMBER PRIMARY KEY,
+    FOREIGN KEY(id) REFERENCES chats(id)
+);
+
+
 CREATE TABLE IF NOT EXISTS chats_mx (
     id         NUMBER PRIMARY KEY,
     sync_start TEXT NOT NULL,
@@ -77,6 +84,15 @@ CREATE TABLE IF NOT EXISTS users_tg (
 );
 
 
+-- Chat photos, doc is the Telegram file id or the Matrix mxc:// url
+CREATE TABLE IF NOT EXISTS chats_ava (
+    id         NUMBER PRIMARY KEY,
+    last_upd   DATETIME NOT NULL,
+    doc        TEXT,
+    FOREIGN KEY(id) REFERENCES chats(id)
+);
+
+
 /*
 
 CREATE INDEX IF NOT EXISTS users_i0
@@ -0,0 +1,454 @@
+use rusqlite::Connection;
+use openssl::sha::sha256;
+use std::collections::HashSet;
+use std::env;
+use libc;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::symlink;
+use std::os::unix::io::AsRawFd;
+use std::process::{self, Command, ExitStatus};
+use std::time::{Duration, UNIX_EPOCH};
+use super::chart::{self, escape};
+use super::db::{self, Reply};
+use super::db_util;
+use super::error::MyError;
+
+/// Avatar root unless `AVA_DIR` is set.
+const DEFAULT_DIR: &str = "./ava";
+
+// Photos change at most once per sync, placeholders may be replaced by a
+// photo on the next one.
+const CACHE_PHOTO: &str = "public, max-age=86400";
+const CACHE_PLACEHOLDER: &str = "public, max-age=3600";
+
+/// Thumbnail sizes in pixels, generated on sync and served as `?size=`.
+pub const SIZES: [u32; 3] = [32, 64, 128];
+
+// ImageMagick, used to make thumbnails and convert non-JPEG avatars. It is
+// a runtime dependency, see default.nix.
+const CONVERT: &str = "convert";
+
+// Leftovers of writes interrupted for longer than this are collected
+const TMP_AGE: Duration = Duration::from_secs(60 * 60 * 24);
+
+pub struct Avatar {
+    pub content_type: &'static str,
+    pub etag: String,
+    pub cache_control: &'static str,
+    // None if the client's copy matches `etag`
+    pub body: Option<Vec<u8>>,
+}
+
+/// Avatar root. Photos are stored once per content as
+/// `objects/<sha256>.jpg`, with thumbnails next to them, and
+/// `<rnd_id>.jpg` is a symlink to the photo of a user or chat.
+pub fn dir() -> String {
+    env::var("AVA_DIR").unwrap_or_else(|_| String::from(DEFAULT_DIR))
+}
+
+fn objects() -> String {
+    format!("{}/objects", dir())
+}
+
+pub fn path(rnd_id: &str) -> String {
+    format!("{}/{}.jpg", dir(), rnd_id)
+}
+
+/// The photo of `rnd_id` without extension, following its link. Avatars
+/// saved before content addressing are plain files named by `rnd_id`.
+fn base(rnd_id: &str) -> String {
+    match fs::read_link(path(rnd_id)) {
+        Ok(target) => {
+            format!("{}/{}", dir(), target.with_extension("").display())
+        }
+        Err(_) => format!("{}/{}", dir(), rnd_id),
+    }
+}
+
+/// Thumbnail of `size` pixels, `ext` is either `jpg` or `webp`.
+pub fn thumb_path(rnd_id: &str, size: u32, ext: &str) -> String {
+    format!("{}-{}.{}", base(rnd_id), size, ext)
+}
+
+/// Name for a temporary sibling of `dst`, unique per process so that
+/// concurrent syncs don't write the same file.
+fn tmp_path(dst: &str) -> String {
+    format!("{}.{}.tmp", dst, process::id())
+}
+
+/// Writes `data` to `dst` through a temporary file, so that readers see
+/// either the old file or the whole new one.
+fn write_atomic(dst: &str, data: &[u8]) -> Result<(), MyError> {
+    let tmp = tmp_path(dst);
+    let res = File::create(&tmp)
+        .and_then(|mut x| x.write_all(data).and_then(|()| x.sync_all()))
+        .and_then(|()| fs::rename(&tmp, dst));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    Ok(res?)
+}
+
+/// Runs `CONVERT`, telling a missing ImageMagick apart from a failed
+/// conversion.
+fn run_convert(cmd: &mut Command) -> Result<ExitStatus, MyError> {
+    cmd.status().map_err(|e| match e.kind() {
+        ErrorKind::NotFound => io::Error::new(
+            ErrorKind::NotFound,
+            format!("{} not found, ImageMagick is required", CONVERT),
+        ).into(),
+        _ => e.into(),
+    })
+}
+
+fn convert(src: &str, size: u32, dst: &str, ext: &str) -> Result<(), MyError> {
+    let geometry = format!("{}x{}", size, size);
+    let tmp = tmp_path(dst);
+    let status = run_convert(Command::new(CONVERT)
+        .arg(src)
+        .args(["-thumbnail", &format!("{}^", geometry)])
+        .args(["-gravity", "center", "-extent", &geometry])
+        // The format is given explicitly, the temporary name has none
+        .arg(format!("{}:{}", ext, tmp)))?;
+    if !status.success() {
+        let _ = fs::remove_file(&tmp);
+        return Err(io::Error::other(
+            format!("{} {} -> {}: {}", CONVERT, src, dst, status),
+        ).into());
+    }
+    fs::rename(&tmp, dst)?;
+    Ok(())
+}
+
+/// Makes JPEG and WebP thumbnails of a saved avatar in all `SIZES`. WebP
+/// support depends on the ImageMagick build, so failing that is only
+/// reported.
+pub fn make_thumbs(rnd_id: &str) -> Result<(), MyError> {
+    let base = base(rnd_id);
+    let src = format!("{}.jpg", base);
+    for &size in SIZES.iter() {
+        let thumb = |ext| format!("{}-{}.{}", base, size, ext);
+        convert(&src, size, &thumb("jpg"), "jpg")?;
+        if let Err(e) = convert(&src, size, &thumb("webp"), "webp") {
+            println!("No WebP thumbnail: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Thumbnails named by `rnd_id`, from before content addressing.
+fn remove_legacy(rnd_id: &str) -> Result<(), MyError> {
+    let mut files = Vec::new();
+    for &size in SIZES.iter() {
+        files.push(format!("{}/{}-{}.jpg", dir(), rnd_id, size));
+        files.push(format!("{}/{}-{}.webp", dir(), rnd_id, size));
+    }
+    for file in files.iter() {
+        match fs::remove_file(file) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+            x => x?,
+        }
+    }
+    Ok(())
+}
+
+/// Saves a JPEG avatar of `rnd_id` and points its link at it. Thumbnails
+/// are left to `make_thumbs`.
+pub fn save(rnd_id: &str, data: &[u8]) -> Result<(), MyError> {
+    let hash: String = sha256(data).iter().map(|x| format!("{:02x}", x))
+        .collect();
+    let target = format!("objects/{}.jpg", hash);
+    fs::create_dir_all(objects())?;
+    let object = format!("{}/{}", dir(), target);
+    if fs::metadata(&object).is_err() {
+        write_atomic(&object, data)?;
+    }
+
+    // Replacing the link by rename also replaces a legacy plain file
+    let link = path(rnd_id);
+    let tmp = tmp_path(&link);
+    let _ = fs::remove_file(&tmp);
+    symlink(&target, &tmp)?;
+    fs::rename(&tmp, &link)?;
+    remove_legacy(rnd_id)
+}
+
+/// Saves an avatar of `rnd_id` along with its thumbnails. Images that are
+/// not JPEG, as Matrix allows, are converted first.
+pub fn store<R: Read>(
+    rnd_id: &str,
+    data: &mut R,
+    jpeg: bool,
+) -> Result<(), MyError> {
+    let mut buf = Vec::new();
+    data.read_to_end(&mut buf)?;
+    if !jpeg {
+        fs::create_dir_all(objects())?;
+        let src = tmp_path(&format!("{}/{}.src", objects(), rnd_id));
+        let dst = tmp_path(&format!("{}/{}.jpg", objects(), rnd_id));
+        write_atomic(&src, &buf)?;
+        let status = run_convert(Command::new(CONVERT)
+            .arg(&src)
+            .arg(format!("jpg:{}", dst)));
+        fs::remove_file(&src)?;
+        if !status?.success() {
+            let _ = fs::remove_file(&dst);
+            return Err(io::Error::other(
+                format!("{} {}: can't convert to JPEG", CONVERT, src),
+            ).into());
+        }
+        buf = fs::read(&dst)?;
+        fs::remove_file(&dst)?;
+    }
+    save(rnd_id, &buf)?;
+    if let Err(e) = make_thumbs(rnd_id) {
+        println!("Thumbnails for {}: {}", rnd_id, e);
+    }
+    Ok(())
+}
+
+/// Removes the avatar of `rnd_id`, ignoring files already gone. The photo
+/// itself may be shared and is left to `gc`.
+pub fn remove(rnd_id: &str) -> Result<(), MyError> {
+    match fs::remove_file(path(rnd_id)) {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+        x => x?,
+    }
+    remove_legacy(rnd_id)
+}
+
+/// Avatar links and legacy plain files, by `rnd_id`.
+fn rnd_ids() -> Result<Vec<String>, MyError> {
+    let mut out = Vec::new();
+    for entry in fs::read_dir(dir())? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if name.ends_with(".jpg") && !name.contains('-') {
+            out.push(String::from(&name[..name.len() - 4]));
+        }
+    }
+    Ok(out)
+}
+
+/// `flock(2)` on `file`. `File::lock` and friends only came in Rust 1.89.
+fn flock(file: &File, op: libc::c_int) -> io::Result<()> {
+    match unsafe { libc::flock(file.as_raw_fd(), op) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn lock_file() -> Result<File, MyError> {
+    fs::create_dir_all(dir())?;
+    let path = format!("{}/.lock", dir());
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+/// Held by commands that save avatars until dropped, so that `gc` never
+/// sees a photo saved but not linked yet.
+pub fn lock() -> Result<File, MyError> {
+    let file = lock_file()?;
+    flock(&file, libc::LOCK_SH)?;
+    Ok(file)
+}
+
+/// Regenerates thumbnails of every saved avatar.
+pub fn make_all_thumbs() -> Result<(), MyError> {
+    let _lock = lock()?;
+    let mut done = HashSet::new();
+    for rnd_id in rnd_ids()? {
+        if !done.insert(base(&rnd_id)) {
+            continue;
+        }
+        if let Err(e) = make_thumbs(&rnd_id) {
+            println!("Thumbnails for {}: {}", rnd_id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Removes photos no avatar links to, with their thumbnails, and
+/// temporary files left by interrupted writes. Refuses to run during a sync.
+pub fn gc() -> Result<(), MyError> {
+    let lock = lock_file()?;
+    match flock(&lock, libc::LOCK_EX | libc::LOCK_NB) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+            return Err(io::Error::other("avatars are being synced").into())
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut used = HashSet::new();
+    for rnd_id in rnd_ids()? {
+        used.insert(base(&rnd_id));
+    }
+
+    let objects = objects();
+    let mut removed = 0;
+    for folder in [dir(), objects.clone()].iter() {
+        let entries = match fs::read_dir(folder) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let file = format!("{}/{}", folder, name);
+            let unused = if name.ends_with(".tmp") {
+                let age = entry.metadata()?.modified()?.elapsed();
+                age.map(|x| x > TMP_AGE).unwrap_or(false)
+            } else if *folder == objects {
+                // Thumbnails are named `<sha256>-<size>.<ext>`
+                let stem = name.split(['-', '.']).next();
+                let base = format!("{}/{}", folder, stem.unwrap_or(""));
+                !used.contains(&base)
+            } else {
+                false
+            };
+            if unused {
+                match fs::remove_file(&file) {
+                    Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+                    x => x?,
+                }
+                removed += 1;
+            }
+        }
+    }
+    println!("Removed {} files", removed);
+    Ok(())
+}
+
+/// Random ids are alphanumeric, anything else can't name an avatar.
+fn valid_id(rnd_id: &str) -> bool {
+    !rnd_id.is_empty() && rnd_id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Up to two uppercase initials of `name`.
+fn initials(name: &str) -> String {
+    let mut out: String = name
+        .split_whitespace()
+        .filter_map(|x| x.chars().find(|c| c.is_alphanumeric()))
+        .take(2)
+        .flat_map(|c| c.to_uppercase())
+        .collect();
+    if out.is_empty() {
+        out.push('?');
+    }
+    out
+}
+
+fn hash(s: &str) -> u32 {
+    s.bytes().fold(0, |h: u32, b| h.wrapping_mul(31).wrapping_add(b as u32))
+}
+
+pub fn placeholder(rnd_id: &str, name: &str, size: u32) -> String {
+    format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{s}""#,
+            r#" height="{s}" viewBox="0 0 128 128">"#,
+            r#"<rect width="128" height="128" fill="hsl({h}, 55%, 50%)"/>"#,
+            r##"<text x="64" y="64" dy=".35em" fill="#fff""##,
+            r#" font-family="sans-serif" font-size="52""#,
+            r#" text-anchor="middle">"#,
+            "{t}</text></svg>",
+        ),
+        s = size,
+        h = hash(rnd_id) % 360,
+        t = escape(&initials(name)),
+    )
+}
+
+/// Checks an `If-None-Match` header value against `etag`.
+fn matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    match if_none_match {
+        Some(x) => x.split(',').any(|x| x.trim() == etag || x.trim() == "*"),
+        None => false,
+    }
+}
+
+/// Serves the avatar of `rnd_id`, or a thumbnail if `size` is one of
+/// `SIZES`. Falls back to the full photo while thumbnails are missing, and
+/// to a placeholder for users without a photo.
+pub fn get(
+    conn: &Connection,
+    rnd_id: &str,
+    size: Option<u32>,
+    webp: bool,
+    if_none_match: Option<&str>,
+) -> Result<Reply<Avatar>, MyError> {
+    if !valid_id(rnd_id) {
+        return Ok(Err((404, db::ERR_USER_NOT_FOUND)));
+    }
+
+    let mut files = Vec::new();
+    if let Some(size) = size {
+        if webp {
+            files.push((thumb_path(rnd_id, size, "webp"), "image/webp"));
+        }
+        files.push((thumb_path(rnd_id, size, "jpg"), "image/jpeg"));
+    }
+    files.push((path(rnd_id), "image/jpeg"));
+
+    for &(ref file, content_type) in files.iter() {
+        let meta = match fs::metadata(file) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        // Same scheme as nginx: modification time and size, plus the
+        // format since both are served from the same URL
+        let mtime = meta.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+        let etag = format!(
+            "\"{:x}-{:x}{}\"",
+            mtime,
+            meta.len(),
+            if content_type == "image/webp" { "-webp" } else { "" },
+        );
+        let body = if matches(if_none_match, &etag) {
+            None
+        } else {
+            Some(fs::read(file)?)
+        };
+        return Ok(Ok(Avatar {
+            content_type,
+            etag,
+            cache_control: CACHE_PHOTO,
+            body,
+        }));
+    }
+
+    let name: String = match db_util::query_row(
+        conn,
+        "
+            SELECT name FROM users WHERE rnd_id = ?1
+             UNION ALL
+            SELECT name FROM chats WHERE rnd_id = ?1
+             LIMIT 1
+        ",
+        &[&rnd_id],
+        |row| row.get(0),
+    )? {
+        Some(x) => x,
+        None => return Ok(Err((404, db::ERR_USER_NOT_FOUND))),
+    };
+
+    let svg = placeholder(rnd_id, &name, size.unwrap_or(128));
+    let etag = format!("\"p{:x}\"", hash(&svg));
+    Ok(Ok(Avatar {
+        content_type: chart::CONTENT_TYPE,
+        body: if matches(if_none_match, &etag) {
+            None
+        } else {
+            Some(svg.into_bytes())
+        },
+        etag,
+        cache_control: CACHE_PLACEHOLDER,
+    }))
+}
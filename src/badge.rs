@@ -0,0 +1,175 @@
+use rusqlite::Connection;
+use super::chart::escape;
+use super::db::{self, Params, Reply};
+use super::db_util;
+use super::error::MyError;
+
+/// Badges are cheap to recompute but often embedded in busy pages.
+pub const CACHE_CONTROL: &str = "public, max-age=300";
+
+/// Days covered by a badge when no dates are given.
+pub const DEFAULT_DAYS: i64 = 30;
+
+const LABEL_COLOR: &str = "#555";
+const VALUE_COLOR: &str = "#007ec6";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    Messages,
+    MessagesPerDay,
+    ActiveUsers,
+}
+
+impl Metric {
+    /// Parses a badge file name such as `active-users.svg`.
+    pub fn from_file(name: &str) -> Option<Metric> {
+        match name {
+            "messages.svg" => Some(Metric::Messages),
+            "messages-per-day.svg" => Some(Metric::MessagesPerDay),
+            "active-users.svg" => Some(Metric::ActiveUsers),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Metric::Messages => "messages",
+            Metric::MessagesPerDay => "messages/day",
+            Metric::ActiveUsers => "active users",
+        }
+    }
+}
+
+/// Formats a number with at most three significant digits, e.g. `1.2k`.
+pub fn short_number(x: f64) -> String {
+    let units = [("", 1.0), ("k", 1e3), ("M", 1e6)];
+    let mut i = units.iter().rposition(|u| x >= u.1).unwrap_or(0);
+    loop {
+        // Rounded first, so that e.g. 999.6 moves on to the next unit
+        // rather than showing as 1000
+        let x = x / units[i].1;
+        let x = if x < 10.0 { (x * 10.0).round() / 10.0 } else { x.round() };
+        if x >= 1e3 && i + 1 < units.len() {
+            i += 1;
+            continue;
+        }
+        return if x < 10.0 && x.fract() != 0.0 {
+            format!("{:.1}{}", x, units[i].0)
+        } else {
+            format!("{:.0}{}", x, units[i].0)
+        };
+    }
+}
+
+// Rough width of 11px Verdana text, as used by shields.io
+fn text_width(s: &str) -> f64 {
+    s.chars().count() as f64 * 6.5 + 10.0
+}
+
+pub fn render(label: &str, value: &str) -> String {
+    let lw = text_width(label);
+    let vw = text_width(value);
+    format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}""#,
+            r#" height="20" role="img" aria-label="{l}: {v}">"#,
+            "<title>{l}: {v}</title>",
+            r#"<rect width="{lw}" height="20" rx="3" fill="{lc}"/>"#,
+            r#"<rect x="{lw}" width="{vw}" height="20" rx="3" fill="{vc}"/>"#,
+            r#"<rect x="{lw}" width="4" height="20" fill="{vc}"/>"#,
+            r##"<g fill="#fff" text-anchor="middle""##,
+            r#" font-family="Verdana,DejaVu Sans,sans-serif" font-size="11">"#,
+            r#"<text x="{lx}" y="14">{l}</text>"#,
+            r#"<text x="{vx}" y="14">{v}</text>"#,
+            "</g></svg>",
+        ),
+        w = lw + vw,
+        lw = lw,
+        vw = vw,
+        lx = lw / 2.0,
+        vx = lw + vw / 2.0,
+        lc = LABEL_COLOR,
+        vc = VALUE_COLOR,
+        l = escape(label),
+        v = escape(value),
+    )
+}
+
+pub fn query_http(
+    conn: &Connection,
+    chat: &str,
+    p: &Params,
+    metric: Metric,
+) -> (u16, String) {
+    db_util::http(query(conn, chat, p, metric))
+}
+
+pub fn query(
+    conn: &Connection,
+    chat: &str,
+    p: &Params,
+    metric: Metric,
+) -> Result<(u16, String), MyError> {
+    Ok(match badge(conn, chat, p, metric)? {
+        Ok(svg) => (200, svg),
+        Err((status, err)) => (status, String::from(err)),
+    })
+}
+
+pub fn badge(
+    conn: &Connection,
+    chat: &str,
+    p: &Params,
+    metric: Metric,
+) -> Result<Reply<String>, MyError> {
+    let r = match db::stats(conn, chat, p)? {
+        Ok(x) => x,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    let messages: i64 = r.messages_by_user.iter().sum();
+    let days = match p.dates {
+        Some((from, to)) => to - from + 1,
+        None => (r.hours.1 - r.hours.0) / 24 + 1,
+    };
+    let value = match metric {
+        Metric::Messages => messages as f64,
+        Metric::MessagesPerDay => messages as f64 / days.max(1) as f64,
+        Metric::ActiveUsers => r.user_ids.len() as f64,
+    };
+
+    Ok(Ok(render(metric.label(), &short_number(value))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_number_units() {
+        assert_eq!(short_number(0.0), "0");
+        assert_eq!(short_number(5.0), "5");
+        assert_eq!(short_number(2.5), "2.5");
+        assert_eq!(short_number(42.0), "42");
+        assert_eq!(short_number(999.0), "999");
+        assert_eq!(short_number(1000.0), "1k");
+        assert_eq!(short_number(1234.0), "1.2k");
+        assert_eq!(short_number(12_345.0), "12k");
+        assert_eq!(short_number(123_456.0), "123k");
+        assert_eq!(short_number(1_500_000.0), "1.5M");
+        assert_eq!(short_number(2e9), "2000M");
+    }
+
+    #[test]
+    fn short_number_rounding() {
+        assert_eq!(short_number(9.94), "9.9");
+        assert_eq!(short_number(9.96), "10");
+        assert_eq!(short_number(99.6), "100");
+        assert_eq!(short_number(999.4), "999");
+        assert_eq!(short_number(999.6), "1k");
+        assert_eq!(short_number(9_960.0), "10k");
+        assert_eq!(short_number(999_600.0), "1M");
+        assert_eq!(short_number(1_040_000.0), "1M");
+        assert_eq!(short_number(0.04), "0");
+    }
+}
@@ -0,0 +1,290 @@
+use rusqlite::Connection;
+use std::fmt::Write;
+use super::date::{self, Bucket};
+use super::db::{self, Params, QueryResult, Reply};
+use super::db_util;
+use super::error::MyError;
+
+pub const ERR_HEATMAP_BUCKET: &str =
+    r#"{"error":"heatmap needs daily buckets"}"#;
+
+pub const CONTENT_TYPE: &str = "image/svg+xml";
+
+const WIDTH: f64 = 800.0;
+const HEIGHT: f64 = 240.0;
+const PAD_LEFT: f64 = 40.0;
+const PAD_RIGHT: f64 = 10.0;
+const PAD_TOP: f64 = 30.0;
+const PAD_BOTTOM: f64 = 24.0;
+
+const BAR_COLOR: &str = "#4c8bf5";
+
+// Heatmap cell size and spacing, and colors from no messages up to max
+const CELL: f64 = 11.0;
+const CELL_STEP: f64 = 13.0;
+const LEVELS: [&str; 5] =
+    ["#ebedf0", "#c6e48b", "#7bc96f", "#239a3b", "#196127"];
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun",
+    "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Daily,
+    Hours,
+    Heatmap,
+}
+
+impl Kind {
+    /// Parses a chart file name such as `daily.svg`.
+    pub fn from_file(name: &str) -> Option<Kind> {
+        match name {
+            "daily.svg" => Some(Kind::Daily),
+            "hours.svg" => Some(Kind::Hours),
+            "heatmap.svg" => Some(Kind::Heatmap),
+            _ => None,
+        }
+    }
+}
+
+/// Escapes text for use in XML/HTML content and attribute values.
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn open(out: &mut String, width: f64, height: f64, title: &str) {
+    write!(
+        out,
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}""#,
+            r#" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif""#,
+            r##" font-size="11" fill="#333">"##,
+            r##"<rect width="{w}" height="{h}" fill="#fff"/>"##,
+            r#"<text x="{x}" y="18" font-size="13" font-weight="bold">"#,
+            "{t}</text>",
+        ),
+        w = width,
+        h = height,
+        x = PAD_LEFT,
+        t = escape(title),
+    ).unwrap();
+}
+
+/// Bar chart of `values`, labelling the bars for which `label` returns a
+/// value. Each bar has a tooltip from `tooltip`.
+fn bars<L, T>(title: &str, values: &[i64], label: L, tooltip: T) -> String
+where
+    L: Fn(usize) -> Option<String>,
+    T: Fn(usize) -> String,
+{
+    let mut out = String::new();
+    open(&mut out, WIDTH, HEIGHT, title);
+
+    let max = values.iter().cloned().max().unwrap_or(0).max(1);
+    let plot_w = WIDTH - PAD_LEFT - PAD_RIGHT;
+    let plot_h = HEIGHT - PAD_TOP - PAD_BOTTOM;
+    let bottom = PAD_TOP + plot_h;
+    let step = plot_w / values.len().max(1) as f64;
+    let gap = if step > 4.0 { 1.0 } else { 0.0 };
+
+    write!(
+        out,
+        concat!(
+            r#"<text x="{x}" y="{y0}" text-anchor="end">{max}</text>"#,
+            r#"<text x="{x}" y="{y1}" text-anchor="end">0</text>"#,
+            r##"<line x1="{l}" y1="{b}" x2="{r}" y2="{b}" stroke="#999"/>"##,
+        ),
+        x = PAD_LEFT - 4.0,
+        y0 = PAD_TOP + 4.0,
+        y1 = bottom,
+        max = max,
+        l = PAD_LEFT,
+        r = WIDTH - PAD_RIGHT,
+        b = bottom,
+    ).unwrap();
+
+    for (i, &value) in values.iter().enumerate() {
+        let x = PAD_LEFT + i as f64 * step;
+        let h = value as f64 / max as f64 * plot_h;
+        write!(
+            out,
+            concat!(
+                r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}""#,
+                r#" fill="{}"><title>{}</title></rect>"#,
+            ),
+            x,
+            bottom - h,
+            step - gap,
+            h,
+            BAR_COLOR,
+            escape(&tooltip(i)),
+        ).unwrap();
+        if let Some(text) = label(i) {
+            write!(
+                out,
+                r#"<text x="{:.2}" y="{}" text-anchor="middle">{}</text>"#,
+                x + step / 2.0,
+                HEIGHT - 8.0,
+                escape(&text),
+            ).unwrap();
+        }
+    }
+
+    out += "</svg>";
+    out
+}
+
+/// Messages per bucket, labelled with about six evenly spaced dates.
+pub fn daily(r: &QueryResult) -> String {
+    let n = r.daily_messages.len();
+    let every = (n / 6).max(1);
+    bars(
+        &format!("{}: messages per {}", r.title, r.bucket),
+        &r.daily_messages,
+        |i| if i % every == 0 { Some(r.bucket_starts[i].clone()) } else {
+            None
+        },
+        |i| format!(
+            "{}: {} messages, {} users",
+            r.bucket_starts[i],
+            r.daily_messages[i],
+            r.daily_users[i],
+        ),
+    )
+}
+
+/// Messages by hour of the day.
+pub fn hours(r: &QueryResult) -> String {
+    hours_of(&r.title, &r.messages_by_hour)
+}
+
+/// Same as `hours`, for any per-hour counts such as a user's.
+pub fn hours_of(name: &str, messages_by_hour: &[i64; 24]) -> String {
+    bars(
+        &format!("{}: messages by hour", name),
+        messages_by_hour,
+        |i| if i % 3 == 0 { Some(format!("{:02}", i)) } else { None },
+        |i| format!("{:02}:00: {} messages", i, messages_by_hour[i]),
+    )
+}
+
+/// Calendar of daily messages, one column per week starting on Monday.
+/// Only meaningful for daily buckets.
+pub fn heatmap(r: &QueryResult) -> String {
+    let days: Vec<i64> = (0..r.daily_messages.len() as i64)
+        .map(|i| r.start_day + i * r.skip_day)
+        .collect();
+    let monday = Bucket::Week.start(r.start_day);
+    let weeks = days.last().map(|&d| (d - monday) / 7 + 1).unwrap_or(0);
+    let top = PAD_TOP + 16.0;
+    let title = format!("{}: messages per day", r.title);
+
+    // Wide enough for the title at roughly 8px per character
+    let width = PAD_LEFT + weeks as f64 * CELL_STEP + PAD_RIGHT;
+    let width = width.max(PAD_LEFT + 8.0 * title.chars().count() as f64);
+
+    let mut out = String::new();
+    open(&mut out, width, top + 7.0 * CELL_STEP + 8.0, &title);
+
+    for &(row, name) in [(0, "Mon"), (2, "Wed"), (4, "Fri")].iter() {
+        write!(
+            out,
+            r#"<text x="{}" y="{}" text-anchor="end">{}</text>"#,
+            PAD_LEFT - 4.0,
+            top + row as f64 * CELL_STEP + CELL - 1.0,
+            name,
+        ).unwrap();
+    }
+
+    for week in 0..weeks {
+        let (_, m, d) = date::civil_from_days(monday + week * 7);
+        if d <= 7 {
+            write!(
+                out,
+                r#"<text x="{}" y="{}">{}</text>"#,
+                PAD_LEFT + week as f64 * CELL_STEP,
+                top - 4.0,
+                MONTHS[m as usize - 1],
+            ).unwrap();
+        }
+    }
+
+    let max = r.daily_messages.iter().cloned().max().unwrap_or(0).max(1);
+    for (i, &day) in days.iter().enumerate() {
+        let value = r.daily_messages[i];
+        let level = if value == 0 { 0 } else {
+            ((value * 4 + max - 1) / max) as usize
+        };
+        write!(
+            out,
+            concat!(
+                r#"<rect x="{}" y="{}" width="{}" height="{}" rx="2""#,
+                r#" fill="{}"><title>{}: {} messages</title></rect>"#,
+            ),
+            PAD_LEFT + ((day - monday) / 7) as f64 * CELL_STEP,
+            top + ((day + 3) % 7) as f64 * CELL_STEP,
+            CELL,
+            CELL,
+            LEVELS[level],
+            r.bucket_starts[i],
+            value,
+        ).unwrap();
+    }
+
+    out += "</svg>";
+    out
+}
+
+pub fn render(r: &QueryResult, kind: Kind) -> String {
+    match kind {
+        Kind::Daily => daily(r),
+        Kind::Hours => hours(r),
+        Kind::Heatmap => heatmap(r),
+    }
+}
+
+pub fn query_http(
+    conn: &Connection,
+    chat: &str,
+    p: &Params,
+    kind: Kind,
+) -> (u16, String) {
+    db_util::http(query(conn, chat, p, kind))
+}
+
+pub fn query(
+    conn: &Connection,
+    chat: &str,
+    p: &Params,
+    kind: Kind,
+) -> Result<(u16, String), MyError> {
+    Ok(match chart(conn, chat, p, kind)? {
+        Ok(svg) => (200, svg),
+        Err((status, err)) => (status, String::from(err)),
+    })
+}
+
+pub fn chart(
+    conn: &Connection,
+    chat: &str,
+    p: &Params,
+    kind: Kind,
+) -> Result<Reply<String>, MyError> {
+    if kind == Kind::Heatmap && p.bucket != Bucket::Day {
+        return Ok(Err((400, ERR_HEATMAP_BUCKET)));
+    }
+    Ok(db::stats(conn, chat, p)?.map(|r| render(&r, kind)))
+}
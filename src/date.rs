@@ -0,0 +1,325 @@
+// Calendar arithmetic on "days since the Unix epoch", the unit used for
+// `start_day` and friends in the API.
+//
+// Conversions follow http://howardhinnant.github.io/date_algorithms.html
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Years accepted in dates, so that day arithmetic can't overflow.
+pub const MIN_YEAR: i64 = 1970;
+pub const MAX_YEAR: i64 = 9999;
+
+/// Largest count accepted in relative ranges such as `30d`.
+const MAX_LAST: i64 = 100_000;
+
+/// Days since 1970-01-01 for a proleptic Gregorian date. The year must be
+/// within a few million years of the epoch not to overflow.
+pub fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let mp = (if m > 2 { m - 3 } else { m + 9 }) as i64;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Inverse of `days_from_civil`.
+pub fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+/// Formats a day as `YYYY-MM-DD`.
+pub fn format_day(day: i64) -> String {
+    let (y, m, d) = civil_from_days(day);
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+/// Current day in the time zone `offset` hours east of UTC.
+pub fn today(offset: i64) -> i64 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or(0);
+    (secs / 3600 + offset) / 24
+}
+
+fn days_in_month(y: i64, m: u32) -> u32 {
+    let next = if m == 12 {
+        days_from_civil(y + 1, 1, 1)
+    } else {
+        days_from_civil(y, m + 1, 1)
+    };
+    (next - days_from_civil(y, m, 1)) as u32
+}
+
+/// Moves `day` by `months` calendar months, clamping to the month's end.
+/// None if the result is further than `MAX_YEAR` years from year zero.
+fn add_months(day: i64, months: i64) -> Option<i64> {
+    let (y, m, d) = civil_from_days(day);
+    let idx = (y * 12 + m as i64 - 1).checked_add(months)?;
+    let (y, m) = (idx.div_euclid(12), idx.rem_euclid(12) as u32 + 1);
+    if y.abs() > MAX_YEAR {
+        return None;
+    }
+    Some(days_from_civil(y, m, d.min(days_in_month(y, m))))
+}
+
+/// Parses a day given as `YYYY-MM-DD`, `YYYY-MM`, `YYYY`, `today`,
+/// `yesterday` or a raw number of days since the epoch.
+///
+/// Partial dates denote their first day, or their last day if `end` is set.
+/// Years must be four digits within `MIN_YEAR..=MAX_YEAR`, and raw numbers
+/// no later than the last day of `MAX_YEAR`.
+pub fn parse_day(s: &str, today: i64, end: bool) -> Option<i64> {
+    match s {
+        "today" => return Some(today),
+        "yesterday" => return Some(today - 1),
+        _ => (),
+    }
+
+    let parts: Vec<&str> = s.split('-').collect();
+    let mut nums = Vec::new();
+    for part in parts.iter() {
+        if part.is_empty() || !part.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        nums.push(part.parse::<i64>().ok()?);
+    }
+
+    if parts[0].len() != 4 {
+        return match nums.as_slice() {
+            &[n] if n < days_from_civil(MAX_YEAR + 1, 1, 1) => Some(n),
+            _ => None,
+        };
+    }
+    if nums[0] < MIN_YEAR || nums[0] > MAX_YEAR {
+        return None;
+    }
+
+    match *nums.as_slice() {
+        [y] => Some(if end {
+            days_from_civil(y + 1, 1, 1) - 1
+        } else {
+            days_from_civil(y, 1, 1)
+        }),
+        [y, m] if (1..=12).contains(&m) => {
+            let m = m as u32;
+            let d = if end { days_in_month(y, m) } else { 1 };
+            Some(days_from_civil(y, m, d))
+        }
+        [y, m, d] if (1..=12).contains(&m) && d >= 1 => {
+            let (m, d) = (m as u32, d as u32);
+            if d > days_in_month(y, m) {
+                return None;
+            }
+            Some(days_from_civil(y, m, d))
+        }
+        _ => None,
+    }
+}
+
+/// Parses a relative range such as `30d`, `4w`, `6m` or `1y` and returns
+/// its first day, so that the range ends with `today` inclusive.
+pub fn parse_last(s: &str, today: i64) -> Option<i64> {
+    if s.len() < 2 || !s.is_char_boundary(s.len() - 1) {
+        return None;
+    }
+    let (n, unit) = s.split_at(s.len() - 1);
+    let n: i64 = n.parse().ok()?;
+    if !(1..=MAX_LAST).contains(&n) {
+        return None;
+    }
+    let first = match unit {
+        "d" => today.checked_sub(n)?,
+        "w" => today.checked_sub(n.checked_mul(7)?)?,
+        "m" => add_months(today, -n)?,
+        "y" => add_months(today, n.checked_mul(-12)?)?,
+        _ => return None,
+    };
+    first.checked_add(1)
+}
+
+/// Time series granularity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bucket {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl Bucket {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Bucket::Day => "day",
+            Bucket::Week => "week",
+            Bucket::Month => "month",
+            Bucket::Year => "year",
+        }
+    }
+
+    /// Widest `to - from` range that is still allowed, in days.
+    pub fn max_days(&self) -> i64 {
+        match *self {
+            Bucket::Day => 1000,
+            Bucket::Week => 1000 * 7,
+            Bucket::Month => 1000 * 30,
+            Bucket::Year => 1000 * 365,
+        }
+    }
+
+    /// First day of the bucket containing `day`. Weeks start on Monday.
+    pub fn start(&self, day: i64) -> i64 {
+        match *self {
+            Bucket::Day => day,
+            Bucket::Week => day - (day + 3) % 7,
+            Bucket::Month => {
+                let (y, m, _) = civil_from_days(day);
+                days_from_civil(y, m, 1)
+            }
+            Bucket::Year => {
+                let (y, _, _) = civil_from_days(day);
+                days_from_civil(y, 1, 1)
+            }
+        }
+    }
+
+    /// First day of the bucket following the one starting at `start`.
+    pub fn next(&self, start: i64) -> i64 {
+        match *self {
+            Bucket::Day => start + 1,
+            Bucket::Week => start + 7,
+            Bucket::Month => {
+                let (y, m, _) = civil_from_days(start);
+                if m == 12 {
+                    days_from_civil(y + 1, 1, 1)
+                } else {
+                    days_from_civil(y, m + 1, 1)
+                }
+            }
+            Bucket::Year => {
+                let (y, _, _) = civil_from_days(start);
+                days_from_civil(y + 1, 1, 1)
+            }
+        }
+    }
+}
+
+impl FromStr for Bucket {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Bucket::Day),
+            "week" => Ok(Bucket::Week),
+            "month" => Ok(Bucket::Month),
+            "year" => Ok(Bucket::Year),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(y: i64, m: u32, d: u32) -> i64 {
+        days_from_civil(y, m, d)
+    }
+
+    #[test]
+    fn civil_round_trip() {
+        assert_eq!(day(1970, 1, 1), 0);
+        assert_eq!(day(2018, 3, 1), 17591);
+        assert_eq!(civil_from_days(17591), (2018, 3, 1));
+        assert_eq!(format_day(day(2016, 2, 29)), "2016-02-29");
+    }
+
+    #[test]
+    fn parse_day_formats() {
+        let today = day(2018, 6, 15);
+        assert_eq!(parse_day("today", today, false), Some(today));
+        assert_eq!(parse_day("yesterday", today, false), Some(today - 1));
+        assert_eq!(parse_day("17000", today, false), Some(17000));
+        assert_eq!(parse_day("2018-03-01", today, false), Some(17591));
+        assert_eq!(parse_day("2018", today, false), Some(day(2018, 1, 1)));
+        assert_eq!(parse_day("2018", today, true), Some(day(2018, 12, 31)));
+        assert_eq!(parse_day("2018-02", today, true), Some(day(2018, 2, 28)));
+        assert_eq!(parse_day("2016-02", today, true), Some(day(2016, 2, 29)));
+    }
+
+    #[test]
+    fn parse_day_invalid() {
+        let today = day(2018, 6, 15);
+        for s in ["", "-", "x", "-5", "2018-", "2018-13", "2018-00",
+                  "2018-02-29", "2018-04-31", "2018-01-00", "2018-1-1-1"]
+            .iter()
+        {
+            assert_eq!(parse_day(s, today, false), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn parse_day_out_of_range() {
+        let today = day(2018, 6, 15);
+        for s in ["1969", "1969-12-31", "0000", "99999-01-01", "12345-06",
+                  "99999999999", "9223372036854775807",
+                  "99999999999999999999"]
+            .iter()
+        {
+            assert_eq!(parse_day(s, today, false), None, "{}", s);
+            assert_eq!(parse_day(s, today, true), None, "{}", s);
+        }
+        assert_eq!(parse_day("9999", today, true), Some(day(9999, 12, 31)));
+        assert_eq!(parse_day("1970", today, false), Some(0));
+    }
+
+    #[test]
+    fn add_months_clamps() {
+        assert_eq!(add_months(day(2018, 1, 31), 1), Some(day(2018, 2, 28)));
+        assert_eq!(add_months(day(2020, 1, 31), 1), Some(day(2020, 2, 29)));
+        assert_eq!(add_months(day(2018, 3, 31), -1), Some(day(2018, 2, 28)));
+        assert_eq!(add_months(day(2018, 1, 15), -1), Some(day(2017, 12, 15)));
+        assert_eq!(add_months(day(2018, 1, 15), -24), Some(day(2016, 1, 15)));
+        assert_eq!(add_months(day(2018, 12, 1), 13), Some(day(2020, 1, 1)));
+    }
+
+    #[test]
+    fn add_months_overflow() {
+        let today = day(2018, 6, 15);
+        assert_eq!(add_months(today, i64::MAX), None);
+        assert_eq!(add_months(today, i64::MIN), None);
+        assert_eq!(add_months(today, 12 * 10_000), None);
+    }
+
+    #[test]
+    fn parse_last_units() {
+        let today = day(2018, 3, 31);
+        assert_eq!(parse_last("1d", today), Some(today));
+        assert_eq!(parse_last("30d", today), Some(today - 29));
+        assert_eq!(parse_last("2w", today), Some(today - 13));
+        assert_eq!(parse_last("1m", today), Some(day(2018, 3, 1)));
+        assert_eq!(parse_last("1y", today), Some(day(2017, 4, 1)));
+    }
+
+    #[test]
+    fn parse_last_invalid() {
+        let today = day(2018, 3, 31);
+        for s in ["", "d", "0d", "-1d", "1x", "1", "1.5d", "d1", "1й",
+                  "100001d", "9223372036854775807w",
+                  "99999999999999999999d", "99999y"]
+            .iter()
+        {
+            assert_eq!(parse_last(s, today), None, "{}", s);
+        }
+    }
+}
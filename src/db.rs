@@ -1,182 +1,453 @@
 use rusqlite::Connection;
 use rusqlite::types::ToSql;
+use super::date::{self, Bucket};
 use super::db_util;
 use super::error::MyError;
+use super::metrics::{self, Concentration};
 use super::serde_json;
 
 #[derive(Debug, Serialize)]
 pub struct QueryResult {
-    title: String,
-    hours: (i64, i64),
+    pub title: String,
+    // `/ava/<rnd_id>.jpg` if the chat has a photo
+    pub avatar: Option<String>,
+    pub hours: (i64, i64),
 
-    start_day: i64,
-    skip_day: i64,
-    daily_users: Vec<i64>,
-    daily_messages: Vec<i64>,
+    // Requested range, as `YYYY-MM-DD`
+    pub from: Option<String>,
+    pub to: Option<String>,
 
-    messages_by_hour: [i64; 24],
-    messages_by_weekday: [i64; 7],
+    pub bucket: &'static str,
+    pub bucket_starts: Vec<String>,
+    pub start_day: i64,
+    pub skip_day: i64,
+    pub daily_users: Vec<i64>,
+    pub daily_messages: Vec<i64>,
 
-    user_ids: Vec<String>,
-    user_names: Vec<String>,
-    messages_by_user: Vec<i64>,
+    // Distinct users over the 7 and 30 days ending on each day, and
+    // daily_users / monthly_active_users. Only for daily buckets, and only
+    // filled by `stats_rolling`.
+    pub weekly_active_users: Vec<i64>,
+    pub monthly_active_users: Vec<i64>,
+    pub dau_mau: Vec<f64>,
+
+    pub messages_by_hour: [i64; 24],
+    pub messages_by_weekday: [i64; 7],
+
+    pub user_ids: Vec<String>,
+    pub user_names: Vec<String>,
+    pub messages_by_user: Vec<i64>,
+
+    pub concentration: Concentration,
+}
+
+/// Parameters shared by the stats queries.
+#[derive(Debug, Clone, Copy)]
+pub struct Params<'a> {
+    pub dates: Option<(i64, i64)>,
+    pub offset: i64,
+    // Only count these users (all if empty)
+    pub users: &'a [String],
+    // Never count these users
+    pub exclude: &'a [String],
+    pub weekday: Option<u8>,
+    // Local hours window `[from, to)`, wrapping past midnight if `from > to`
+    pub hours: Option<(u8, u8)>,
+    pub bucket: Bucket,
+}
+
+/// Either a value or an HTTP status with one of the `ERR_*` bodies.
+pub type Reply<T> = Result<T, (u16, &'static str)>;
+
+pub fn reply_json<T: ::serde::Serialize>(x: Reply<T>) -> (u16, String) {
+    match x {
+        Ok(x) => (200, serde_json::to_string(&x).unwrap()),
+        Err((status, err)) => (status, String::from(err)),
+    }
+}
+
+/// Condition on `messages` built from `Params`, to be appended to a
+/// `WHERE` clause.
+pub struct Filter {
+    pub sql: String,
+    params: Vec<(&'static str, i64)>,
+}
+
+impl Params<'static> {
+    /// Parameters of queries that only take a range and a time zone, to
+    /// validate them the same way.
+    pub fn range(dates: Option<(i64, i64)>, offset: i64) -> Self {
+        Params {
+            dates,
+            offset,
+            users: &[],
+            exclude: &[],
+            weekday: None,
+            hours: None,
+            bucket: Bucket::Day,
+        }
+    }
+}
+
+impl<'a> Params<'a> {
+    pub fn check(&self) -> Reply<()> {
+        if self.offset < -12 || self.offset > 12 {
+            return Err((400, ERR_INVALID_OFFSET));
+        }
+
+        if let Some(weekday) = self.weekday {
+            if weekday >= 7 {
+                return Err((400, ERR_INVALID_WEEKDAY));
+            }
+        }
+
+        if let Some((from, to)) = self.hours {
+            if from >= 24 || to > 24 || from == to {
+                return Err((400, ERR_INVALID_HOURS));
+            }
+        }
+
+        if let Some((from, to)) = self.dates {
+            let max_days = self.bucket.max_days();
+            if to < from || to - from > max_days {
+                return Err((400, ERR_INVALID_DATES));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Narrows a range taken from the data rather than given by the caller
+    /// to one `check` accepts: the last `max_days` of it.
+    pub fn clamp_dates(&self, (from, to): (i64, i64)) -> (i64, i64) {
+        (from.max(to - self.bucket.max_days()), to)
+    }
+}
+
+impl Filter {
+    /// Builds the filter for already `check`ed parameters.
+    pub fn new(
+        conn: &Connection,
+        p: &Params,
+    ) -> Result<Reply<Filter>, MyError> {
+        let mut filter = Filter {
+            sql: String::new(),
+            params: Vec::new(),
+        };
+
+        if !p.users.is_empty() {
+            let ids = match search_users(conn, p.users) {
+                Some(ids) => ids,
+                None => return Ok(Err((404, ERR_USER_NOT_FOUND))),
+            };
+            filter.sql += &format!("AND messages.user_id IN ({}) ", ids);
+        }
+        if !p.exclude.is_empty() {
+            let ids = match search_users(conn, p.exclude) {
+                Some(ids) => ids,
+                None => return Ok(Err((404, ERR_USER_NOT_FOUND))),
+            };
+            filter.sql += &format!("AND messages.user_id NOT IN ({}) ", ids);
+        }
+        if let Some((from, to)) = p.dates {
+            filter.sql += "AND hour BETWEEN :hour_from AND :hour_to ";
+            filter.params.push((":hour_from", from*24 - p.offset));
+            filter.params.push((":hour_to", to*24 - p.offset + 23));
+        }
+        if let Some(weekday) = p.weekday {
+            filter.sql += "AND (hour + :offset)/24%7 = :weekday ";
+            filter.params.push((":offset", p.offset));
+            filter.params.push((":weekday", (weekday as i64 + 4)%7));
+        }
+        if let Some((from, to)) = p.hours {
+            filter.sql += if from < to {
+                "AND (hour + :offset)%24 >= :local_from
+                 AND (hour + :offset)%24 < :local_to "
+            } else {
+                "AND ((hour + :offset)%24 >= :local_from
+                      OR (hour + :offset)%24 < :local_to) "
+            };
+            filter.params.push((":offset", p.offset));
+            filter.params.push((":local_from", from as i64));
+            filter.params.push((":local_to", to as i64));
+        }
+
+        Ok(Ok(filter))
+    }
+
+    /// Appends the values bound by `self.sql` to `args`.
+    pub fn args<'a>(&'a self, args: &mut Vec<(&'a str, &'a dyn ToSql)>) {
+        for &(name, ref value) in self.params.iter() {
+            args.push((name, value));
+        }
+    }
 }
 
 pub fn query_http(
     conn: &Connection,
     chat: &str,
-    dates: Option<(i64, i64)>,
-    offset: i64,
-    user_id: Option<&str>,
-    weekday: Option<u8>,
+    p: &Params,
 ) -> (u16, String) {
-    match query(conn, chat, dates, offset, user_id, weekday) {
-        Ok(res) => res,
-        Err(e) => (500, format!("Error:\n{:?}", e)),
-    }
+    db_util::http(query(conn, chat, p))
 }
 
-const ERR_INVALID_OFFSET:  &str = r#"{"error":"invalid offset"}"#;
-const ERR_INVALID_DATES:   &str = r#"{"error":"invalid dates"}"#;
-const ERR_INVALID_WEEKDAY: &str = r#"{"error":"invalid weekday"}"#;
-const ERR_CHAT_NOT_FOUND:  &str = r#"{"error":"chat not found"}"#;
-const ERR_USER_NOT_FOUND:  &str = r#"{"error":"user not found"}"#;
+pub const ERR_INVALID_OFFSET:  &str = r#"{"error":"invalid offset"}"#;
+pub const ERR_INVALID_DATES:   &str = r#"{"error":"invalid dates"}"#;
+pub const ERR_INVALID_WEEKDAY: &str = r#"{"error":"invalid weekday"}"#;
+pub const ERR_INVALID_HOURS:   &str = r#"{"error":"invalid hours"}"#;
+pub const ERR_CHAT_NOT_FOUND:  &str = r#"{"error":"chat not found"}"#;
+pub const ERR_USER_NOT_FOUND:  &str = r#"{"error":"user not found"}"#;
 
 pub fn query(
     conn: &Connection,
     chat: &str,
-    dates: Option<(i64, i64)>,
-    offset: i64,
-    user_rid: Option<&str>,
-    weekday: Option<u8>,
+    p: &Params,
 ) -> Result<(u16, String), MyError> {
+    Ok(reply_json(stats_rolling(conn, chat, p)?))
+}
 
-    if offset < -12 || offset > 12 {
-        return Ok((400, String::from(ERR_INVALID_OFFSET)));
-    }
+pub fn stats(
+    conn: &Connection,
+    chat: &str,
+    p: &Params,
+) -> Result<Reply<QueryResult>, MyError> {
+    stats_with(conn, chat, p, false)
+}
 
-    if let Some(weekday) = weekday {
-        if weekday >= 7 {
-            return Ok((400, String::from(ERR_INVALID_WEEKDAY)));
-        }
-    }
+/// Like `stats`, with the rolling active user series for daily buckets.
+pub fn stats_rolling(
+    conn: &Connection,
+    chat: &str,
+    p: &Params,
+) -> Result<Reply<QueryResult>, MyError> {
+    stats_with(conn, chat, p, true)
+}
 
-    if let Some((from, to)) = dates {
-        if from < 17000 || to < 17000 || to - from > 1000 {
-            return Ok((400, String::from(ERR_INVALID_DATES)));
-        }
+fn stats_with(
+    conn: &Connection,
+    chat: &str,
+    p: &Params,
+    rolling: bool,
+) -> Result<Reply<QueryResult>, MyError> {
+    let Params { dates, offset, weekday, bucket, .. } = *p;
+
+    if let Err(e) = p.check() {
+        return Ok(Err(e));
     }
 
+    let filter = match Filter::new(conn, p)? {
+        Ok(x) => x,
+        Err(e) => return Ok(Err(e)),
+    };
+
     let (chat_id, chat_title) = match search_chat(conn, chat) {
         Some(x) => x,
-        None => return Ok((404, String::from(ERR_CHAT_NOT_FOUND))),
+        None => return Ok(Err((404, ERR_CHAT_NOT_FOUND))),
     };
 
+    let avatar = db_util::query_row(
+        conn,
+        "
+            SELECT chats.rnd_id
+              FROM chats
+             INNER JOIN chats_ava ON chats_ava.id = chats.id
+             WHERE chats.id = ?
+               AND chats_ava.doc IS NOT NULL
+        ",
+        &[&chat_id],
+        |row| format!("/ava/{}.jpg", row.get::<_, String>(0)),
+    )?;
+
     let mut result = QueryResult {
         title: chat_title,
+        avatar,
         hours: (0, 0),
 
+        from: dates.map(|x| date::format_day(x.0)),
+        to: dates.map(|x| date::format_day(x.1)),
+
+        bucket: bucket.name(),
+        bucket_starts: Vec::new(),
         start_day: 0,
         skip_day: 1,
         daily_users: Vec::new(),
         daily_messages: Vec::new(),
 
+        weekly_active_users: Vec::new(),
+        monthly_active_users: Vec::new(),
+        dau_mau: Vec::new(),
+
         messages_by_hour: [0; 24],
         messages_by_weekday: [0; 7],
 
         user_ids: Vec::new(),
         user_names: Vec::new(),
         messages_by_user: Vec::new(),
-    };
-
-    let mut _user_id: i64 = 0;
-    let mut _weekday: u8 = 0;
-    let hours = dates.map(|(a,b)| (a, a*24 - offset, b*24 - offset + 23));
-    let mut args: Vec<(&str, &ToSql)> = Vec::new();
-    args.push((":chat_id", &chat_id));
-    args.push((":offset", &offset));
 
-    let mut filter = String::from("");
-    if let Some(user_rid) = user_rid.as_ref() {
-        let user_id = match search_user(conn, user_rid) {
-            Some(user_id) => user_id,
-            None => return Ok((404, String::from(ERR_USER_NOT_FOUND))),
-        };
-
-        filter += "AND :user_id = messages.user_id ";
-        _user_id = user_id;
-        args.push((":user_id",  &_user_id));
-    }
-    if let Some(hours) = hours.as_ref() {
-        filter += "AND hour BETWEEN :hour_from AND :hour_to ";
-        result.start_day = hours.0;
+        concentration: metrics::concentration(&[]),
+    };
 
-        args.push((":hour_from", &hours.1));
-        args.push((":hour_to",   &hours.2));
+    if let Some((from, _)) = dates {
+        result.start_day = from;
     }
     if let Some(weekday) = weekday {
-        filter += "AND (hour + :offset)/24%7 = :weekday";
-        _weekday = (weekday + 4)%7;
         result.skip_day = 7;
         if result.start_day != 0 {
             result.start_day += 6 - (result.start_day - weekday as i64 + 2) % 7;
         }
-        args.push((":weekday",  &_weekday));
     }
+
+    let mut args: Vec<(&str, &dyn ToSql)> = Vec::new();
+    args.push((":chat_id", &chat_id));
+    args.push((":offset", &offset));
+    filter.args(&mut args);
+    let filter = &filter.sql;
     let args = args.as_slice();
 
-    let mut prev_day = result.start_day - 1;
-    db_util::query_map_named(
-        &conn,
-        format!("
-            SELECT (hour + :offset)/24
-                 , COUNT(DISTINCT user_id)
-                 , SUM(count)
-              FROM messages
-             WHERE chat_id = :chat_id
-                   {}
-             GROUP BY (hour + :offset)/24
-        ", filter).as_ref(),
-        args,
-        |row| {
-            let day = row.get(0);
-            if result.start_day == 0 {
-                result.start_day = day;
-            } else {
-                for d in prev_day + 1..day {
-                    if let Some(weekday) = weekday {
-                        if weekday as i64 != (d+3) % 7 {
-                            continue
+    if bucket == Bucket::Day {
+        let mut prev_day = result.start_day - 1;
+        db_util::query_map_named(
+            conn,
+            format!("
+                SELECT (hour + :offset)/24
+                     , COUNT(DISTINCT user_id)
+                     , SUM(count)
+                  FROM messages
+                 WHERE chat_id = :chat_id
+                       {}
+                 GROUP BY (hour + :offset)/24
+            ", filter).as_ref(),
+            args,
+            |row| {
+                let day = row.get(0);
+                if result.start_day == 0 {
+                    result.start_day = day;
+                } else {
+                    for d in prev_day + 1..day {
+                        if let Some(weekday) = weekday {
+                            if weekday as i64 != (d+3) % 7 {
+                                continue
+                            }
                         }
+                        result.daily_users.push(0);
+                        result.daily_messages.push(0);
                     }
-                    result.daily_users.push(0);
-                    result.daily_messages.push(0);
                 }
-            }
-            prev_day = day;
-            if let Some(weekday) = weekday {
-                if weekday as i64 != (day + 3) % 7 {
-                    println!("Shit! {} {}", weekday, (day + 3) % 7);
+                prev_day = day;
+                if let Some(weekday) = weekday {
+                    if weekday as i64 != (day + 3) % 7 {
+                        println!("Shit! {} {}", weekday, (day + 3) % 7);
+                    }
                 }
-            }
-            result.daily_users.push(row.get(1));
-            result.daily_messages.push(row.get(2));
-        },
-    )?;
-    if let Some(dates) = dates.as_ref() {
-        for d in prev_day..dates.1 {
-            if let Some(weekday) = weekday {
-                if weekday as i64 != (d+4) % 7 {
-                    continue
+                result.daily_users.push(row.get(1));
+                result.daily_messages.push(row.get(2));
+            },
+        )?;
+        if let Some(dates) = dates.as_ref() {
+            for d in prev_day..dates.1 {
+                if let Some(weekday) = weekday {
+                    if weekday as i64 != (d+4) % 7 {
+                        continue
+                    }
                 }
+                result.daily_users.push(0);
+                result.daily_messages.push(0);
+            }
+        }
+        for i in 0..result.daily_users.len() as i64 {
+            let day = result.start_day + i * result.skip_day;
+            result.bucket_starts.push(date::format_day(day));
+        }
+    } else {
+        let mut rows: Vec<(i64, i64, i64)> = Vec::new();
+        db_util::query_map_named(
+            conn,
+            format!("
+                SELECT {}
+                     , COUNT(DISTINCT user_id)
+                     , SUM(count)
+                  FROM messages
+                 WHERE chat_id = :chat_id
+                       {}
+                 GROUP BY 1
+                 ORDER BY 1
+            ", bucket_sql(bucket), filter).as_ref(),
+            args,
+            |row| rows.push((row.get(0), row.get(1), row.get(2))),
+        )?;
+
+        let first = match dates {
+            Some((from, _)) => Some(bucket.start(from)),
+            None => rows.first().map(|x| x.0),
+        };
+        let last = match dates {
+            Some((_, to)) => Some(bucket.start(to)),
+            None => rows.last().map(|x| x.0),
+        };
+        if let (Some(first), Some(last)) = (first, last) {
+            result.start_day = first;
+            result.skip_day = if bucket == Bucket::Week { 7 } else { 0 };
+            let mut rows = rows.iter().peekable();
+            let mut day = first;
+            while day <= last {
+                let (users, messages) = match rows.peek() {
+                    Some(&&(d, users, messages)) if d == day => {
+                        rows.next();
+                        (users, messages)
+                    }
+                    _ => (0, 0),
+                };
+                result.bucket_starts.push(date::format_day(day));
+                result.daily_users.push(users);
+                result.daily_messages.push(messages);
+                day = bucket.next(day);
             }
-            result.daily_users.push(0);
-            result.daily_messages.push(0);
+        }
+    }
+
+    if rolling && bucket == Bucket::Day && !result.daily_users.is_empty() {
+        // Look 29 days before the range so the first days get full windows.
+        let mut wide = *p;
+        wide.dates = dates.map(|(from, to)| (from - 29, to));
+        let wide = match Filter::new(conn, &wide)? {
+            Ok(x) => x,
+            Err(e) => return Ok(Err(e)),
+        };
+        let mut args: Vec<(&str, &dyn ToSql)> = Vec::new();
+        args.push((":chat_id", &chat_id));
+        args.push((":offset", &offset));
+        wide.args(&mut args);
+
+        let mut active = Vec::new();
+        db_util::query_map_named(
+            conn,
+            format!("
+                SELECT DISTINCT (hour + :offset)/24 AS day, user_id
+                  FROM messages
+                 WHERE chat_id = :chat_id
+                       {}
+                 ORDER BY day
+            ", wide.sql).as_ref(),
+            args.as_slice(),
+            |row| active.push((row.get::<_, i64>(0), row.get::<_, i64>(1))),
+        )?;
+
+        let days: Vec<i64> = (0..result.daily_users.len() as i64)
+            .map(|i| result.start_day + i * result.skip_day)
+            .collect();
+        let rolling = metrics::rolling_active(&active, &days);
+        for (i, &(wau, mau)) in rolling.iter().enumerate() {
+            let dau = result.daily_users[i];
+            result.weekly_active_users.push(wau);
+            result.monthly_active_users.push(mau);
+            result.dau_mau.push(if mau == 0 { 0.0 } else {
+                dau as f64 / mau as f64
+            });
         }
     }
 
     db_util::query_map_named(
-        &conn,
+        conn,
         format!("
             SELECT (hour + :offset) % 24
                  , SUM(count)
@@ -185,7 +456,7 @@ pub fn query(
                    {}
              GROUP BY (hour + :offset) % 24
         ", filter).as_ref(),
-        &args,
+        args,
         |row| {
             let hour: i64 = row.get(0);
             result.messages_by_hour[hour as usize] = row.get(1);
@@ -193,7 +464,7 @@ pub fn query(
     )?;
 
     db_util::query_map_named(
-        &conn,
+        conn,
         format!("
             SELECT ((hour + :offset)/24 + 3)%7, SUM(count)
               FROM messages
@@ -201,7 +472,7 @@ pub fn query(
                    {}
              GROUP BY ((hour + :offset)/24 + 3)%7
         ", filter).as_ref(),
-        &args,
+        args,
         |row| {
             let weekday: i64 = row.get(0);
             result.messages_by_weekday[weekday as usize] = row.get(1);
@@ -209,7 +480,7 @@ pub fn query(
     )?;
 
     db_util::query_map_named(
-        &conn,
+        conn,
         format!("
             SELECT users.rnd_id
                  , users.name
@@ -222,13 +493,14 @@ pub fn query(
              GROUP BY(messages.user_id)
              ORDER BY SUM(COUNT) DESC
         ", filter).as_ref(),
-        &args,
+        args,
         |row| {
             result.user_ids.push(row.get(0));
             result.user_names.push(row.get(1));
             result.messages_by_user.push(row.get(2));
         },
     )?;
+    result.concentration = metrics::concentration(&result.messages_by_user);
 
     result.hours = conn.query_row_named(
         "
@@ -240,10 +512,26 @@ pub fn query(
         |row| (row.get::<_, i64>(0), row.get::<_, i64>(1)),
     )?;
 
-    Ok((200, serde_json::to_string(&result).unwrap()))
+    Ok(Ok(result))
+}
+
+/// SQL expression for the first day of the bucket containing `hour`.
+fn bucket_sql(bucket: Bucket) -> &'static str {
+    match bucket {
+        Bucket::Day => "(hour + :offset)/24",
+        Bucket::Week => "(hour + :offset)/24 - ((hour + :offset)/24 + 3)%7",
+        Bucket::Month => "
+            CAST(julianday((hour + :offset)*3600, 'unixepoch',
+                           'start of month') - 2440587.5 AS INTEGER)
+        ",
+        Bucket::Year => "
+            CAST(julianday((hour + :offset)*3600, 'unixepoch',
+                           'start of year') - 2440587.5 AS INTEGER)
+        ",
+    }
 }
 
-fn search_chat(conn: &Connection, chat: &str) -> Option<(i64, String)> {
+pub fn search_chat(conn: &Connection, chat: &str) -> Option<(i64, String)> {
     let res = conn.query_row(
         "
             SELECT id, name
@@ -254,13 +542,20 @@ fn search_chat(conn: &Connection, chat: &str) -> Option<(i64, String)> {
         &[&chat],
         |row| (row.get(0), row.get(1)),
     );
-    match res {
-        Ok(x) => Some(x),
-        Err(_) => None,
+    res.ok()
+}
+
+/// Resolves a list of random ids into a comma-separated list of internal
+/// ids, suitable for an `IN (...)` clause.
+fn search_users(conn: &Connection, random_ids: &[String]) -> Option<String> {
+    let mut ids = Vec::new();
+    for random_id in random_ids.iter() {
+        ids.push(search_user(conn, random_id)?.to_string());
     }
+    Some(ids.join(","))
 }
 
-fn search_user(conn: &Connection, random_id: &str) -> Option<i64> {
+pub fn search_user(conn: &Connection, random_id: &str) -> Option<i64> {
     let res = conn.query_row(
         "
             SELECT id
@@ -270,8 +565,31 @@ fn search_user(conn: &Connection, random_id: &str) -> Option<i64> {
         &[&random_id],
         |row| row.get::<_, i64>(0),
     );
-    match res {
-        Ok(x) => Some(x),
-        Err(_) => None,
+    res.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_dates() {
+        let day = |y, m, d| date::days_from_civil(y, m, d);
+        let mut p = Params::range(Some((day(2016, 1, 1), day(2019, 6, 1))), 0);
+        assert_eq!(p.check(), Err((400, ERR_INVALID_DATES)));
+        p.bucket = Bucket::Year;
+        assert_eq!(p.check(), Ok(()));
+        p.dates = Some((day(2016, 1, 1), day(2016, 12, 31)));
+        p.bucket = Bucket::Day;
+        assert_eq!(p.check(), Ok(()));
+        p.dates = Some((day(2016, 2, 1), day(2016, 1, 1)));
+        assert_eq!(p.check(), Err((400, ERR_INVALID_DATES)));
+    }
+
+    #[test]
+    fn clamp_dates_keeps_last_days() {
+        let p = Params::range(None, 0);
+        assert_eq!(p.clamp_dates((100, 5000)), (4000, 5000));
+        assert_eq!(p.clamp_dates((4500, 5000)), (4500, 5000));
     }
 }
@@ -0,0 +1,214 @@
+use futures::Future;
+use reqwest::{self, Url};
+use reqwest::header::ContentType;
+use reqwest::mime;
+use rusqlite::Connection;
+use std::io;
+use super::ava;
+use super::db_tg_ava::{self, Bot, Pending, Session, Target};
+use super::db_util;
+use super::error::MyError;
+use telegram_bot_raw::{
+    self,
+    HttpRequest,
+    JsonIdResponse,
+    JsonRequestType,
+    Request,
+    RequestType,
+    RequestUrl,
+};
+
+/// `getChat` returning only the chat photo, which `telegram_bot::Chat`
+/// doesn't have.
+#[derive(Debug, Clone, Serialize)]
+struct GetChatPhoto {
+    chat_id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatPhotoInfo {
+    photo: Option<ChatPhoto>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatPhoto {
+    big_file_id: String,
+}
+
+impl Request for GetChatPhoto {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonIdResponse<ChatPhotoInfo>;
+
+    fn serialize(&self) -> Result<HttpRequest, telegram_bot_raw::Error> {
+        Self::Type::serialize(RequestUrl::method("getChat"), self)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MxAvatar {
+    url: String,
+}
+
+#[derive(Debug)]
+struct Row {
+    id:     i64,
+    rnd_id: String,
+    ext_id: String,
+    old:    Option<String>,
+}
+
+/// Chats of `kind` not checked during the last day.
+fn db_get_rows(conn: &Connection, kind: i64) -> Result<Vec<Row>, MyError> {
+    let mut rows = Vec::new();
+    db_util::query_map_named(
+        conn,
+        "
+            SELECT chats.id, chats.rnd_id, CAST(chats.ext_id AS TEXT)
+                 , chats_ava.doc
+              FROM chats
+              LEFT JOIN chats_ava
+                     ON chats_ava.id = chats.id
+             WHERE chats.kind = :kind
+               AND (chats_ava.last_upd IS NULL
+                    OR chats_ava.last_upd + 60*60*24
+                     < CAST(strftime('%s', 'now') AS INTEGER))
+        ",
+        &[(":kind", &kind)],
+        |row| {
+            rows.push(Row {
+                id:     row.get(0),
+                rnd_id: row.get(1),
+                ext_id: row.get(2),
+                old:    row.get(3),
+            })
+        },
+    )?;
+    Ok(rows)
+}
+
+fn db_set_have(
+    conn: &Connection,
+    id: i64,
+    doc: &Option<String>,
+) -> Result<(), MyError> {
+    conn.execute(
+        "
+            INSERT OR REPLACE INTO chats_ava(id, last_upd, doc)
+            VALUES (?, +strftime('%s', 'now'), ?)
+        ",
+        &[&id, doc]
+    )?;
+    Ok(())
+}
+
+fn tg_photo(bot: &Bot, chat_id: i64) -> Pending<Option<String>> {
+    Box::new(
+        bot.call(GetChatPhoto { chat_id })
+            .map(|x| x.photo.map(|x| x.big_file_id)),
+    )
+}
+
+/// Syncs photos of Telegram chats within the session of `sync-tg-ava`.
+pub fn update_tg(
+    conn: &mut Connection,
+    s: &mut Session,
+) -> Result<(), MyError> {
+    let mut rows = Vec::new();
+    for row in db_get_rows(conn, 0)? {
+        match row.ext_id.parse() {
+            Ok(chat_id) => rows.push((
+                Target { id: row.id, rnd_id: row.rnd_id, old: row.old },
+                chat_id,
+            )),
+            Err(_) => println!("Bad telegram chat id {}", row.ext_id),
+        }
+    }
+    db_tg_ava::run(conn, s, rows, tg_photo, db_set_have)?;
+    Ok(())
+}
+
+fn mx_url(homeserver: &Url, path: &[&str], token: &str) -> Url {
+    let mut url = homeserver.clone();
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.pop_if_empty().extend(path);
+    }
+    url.query_pairs_mut().append_pair("access_token", token);
+    url
+}
+
+fn update_mx_row(
+    conn: &Connection,
+    client: &reqwest::Client,
+    homeserver: &Url,
+    token: &str,
+    row: &Row,
+) -> Result<(), MyError> {
+    let url = mx_url(
+        homeserver,
+        &["_matrix", "client", "r0", "rooms", &row.ext_id, "state",
+          "m.room.avatar"],
+        token,
+    );
+    let mut resp = client.get(url).send()?;
+    let new = if resp.status() == reqwest::StatusCode::NotFound {
+        None
+    } else if resp.status().is_success() {
+        // An empty url means the avatar was removed
+        Some(resp.json::<MxAvatar>()?.url).filter(|x| !x.is_empty())
+    } else {
+        println!("Error: {} {}", row.ext_id, resp.status());
+        return Ok(());
+    };
+
+    if new != row.old {
+        match new {
+            Some(ref url) if url.starts_with("mxc://") => {
+                let media = &url["mxc://".len()..];
+                let mut path = vec!["_matrix", "media", "r0", "download"];
+                path.extend(media.splitn(2, '/'));
+                let url = mx_url(homeserver, &path, token);
+                let mut resp = client.get(url).send()?;
+                if !resp.status().is_success() {
+                    println!("Error: {} {}", media, resp.status());
+                    return Ok(());
+                }
+                let jpeg = match resp.headers().get::<ContentType>() {
+                    Some(x) => x.0 == mime::IMAGE_JPEG,
+                    None => false,
+                };
+                ava::store(&row.rnd_id, &mut resp, jpeg)?;
+            }
+            Some(ref url) => {
+                println!("Error: unsupported avatar url {}", url);
+                return Ok(());
+            }
+            None => {
+                println!("Removing {}", row.rnd_id);
+                ava::remove(&row.rnd_id)?;
+            }
+        }
+    }
+    db_set_have(conn, row.id, &new)?;
+    Ok(())
+}
+
+/// Syncs `m.room.avatar` of Matrix rooms from `homeserver`.
+pub fn update_mx(
+    conn: &Connection,
+    homeserver: &str,
+    token: &str,
+) -> Result<(), MyError> {
+    let homeserver = Url::parse(homeserver).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+    })?;
+    let client = reqwest::Client::new();
+    let _lock = ava::lock()?;
+
+    for row in db_get_rows(conn, 1)?.iter() {
+        println!("{:?}", row);
+        if let Err(e) = update_mx_row(conn, &client, &homeserver, token, row) {
+            println!("Err: {}", e);
+        }
+    }
+    Ok(())
+}
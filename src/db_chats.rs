@@ -0,0 +1,300 @@
+use rusqlite::Connection;
+use rusqlite::types::ToSql;
+use super::date;
+use super::db::{self, Reply};
+use super::db_util;
+use super::error::MyError;
+use super::serde_json;
+
+pub const MAX_LIMIT: i64 = 100;
+
+const ERR_INVALID_SORT:  &str = r#"{"error":"invalid sort"}"#;
+const ERR_INVALID_LIMIT: &str = r#"{"error":"invalid limit"}"#;
+const ERR_INVALID_PAGE:  &str = r#"{"error":"invalid page"}"#;
+
+#[derive(Debug, Serialize)]
+pub struct ChatsResult {
+    pub total: i64,
+
+    pub chat_ids: Vec<String>,
+    pub chat_aliases: Vec<Option<String>>,
+    pub chat_names: Vec<String>,
+    pub messages: Vec<i64>,
+    pub active_users: Vec<i64>,
+    pub last_hours: Vec<Option<i64>>,
+    // `/ava/<rnd_id>.jpg` if the chat has a photo
+    pub chat_avatars: Vec<Option<String>>,
+}
+
+pub fn query_http(
+    conn: &Connection,
+    q: &str,
+    sort: Option<&str>,
+    page: i64,
+    limit: i64,
+) -> (u16, String) {
+    db_util::http(query(conn, q, sort, page, limit))
+}
+
+pub fn query(
+    conn: &Connection,
+    q: &str,
+    sort: Option<&str>,
+    page: i64,
+    limit: i64,
+) -> Result<(u16, String), MyError> {
+    Ok(db::reply_json(list(conn, q, sort, page, limit)?))
+}
+
+pub fn list(
+    conn: &Connection,
+    q: &str,
+    sort: Option<&str>,
+    page: i64,
+    limit: i64,
+) -> Result<Reply<ChatsResult>, MyError> {
+    if !(1..=MAX_LIMIT).contains(&limit) || page < 0 {
+        return Ok(Err((400, ERR_INVALID_LIMIT)));
+    }
+
+    // Prefix matches go first when searching, unless asked otherwise.
+    let default_sort = if q.is_empty() { "messages" } else { "relevance" };
+    let order = match sort.unwrap_or(default_sort) {
+        "relevance" => "name LIKE :prefix ESCAPE '\\' OR alias LIKE :prefix ESCAPE '\\' \
+               DESC, 4 DESC",
+        "messages" => "4 DESC",
+        "users" => "5 DESC, 4 DESC",
+        "activity" => "6 DESC",
+        "name" => "3 COLLATE NOCASE",
+        _ => return Ok(Err((400, ERR_INVALID_SORT))),
+    };
+
+    let mut result = ChatsResult {
+        total: 0,
+
+        chat_ids: Vec::new(),
+        chat_aliases: Vec::new(),
+        chat_names: Vec::new(),
+        messages: Vec::new(),
+        active_users: Vec::new(),
+        last_hours: Vec::new(),
+        chat_avatars: Vec::new(),
+    };
+
+    let escaped = q
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    let pattern = format!("%{}%", escaped);
+    let prefix = format!("{}%", escaped);
+    let skip = match page.checked_mul(limit) {
+        Some(x) => x,
+        None => return Ok(Err((400, ERR_INVALID_PAGE))),
+    };
+
+    let filter = "
+        chats.id NOT IN (SELECT id FROM chats_unlisted)
+        AND (name LIKE :pattern ESCAPE '\\' OR alias LIKE :pattern ESCAPE '\\')
+    ";
+
+    result.total = conn.query_row_named(
+        format!("SELECT COUNT(*) FROM chats WHERE {}", filter).as_ref(),
+        &[(":pattern", &pattern)],
+        |row| row.get(0),
+    )?;
+
+    let mut args: Vec<(&str, &dyn ToSql)> = Vec::new();
+    args.push((":pattern", &pattern));
+    args.push((":limit", &limit));
+    args.push((":skip", &skip));
+    if order.contains(":prefix") {
+        args.push((":prefix", &prefix));
+    }
+
+    db_util::query_map_named(
+        conn,
+        format!("
+            SELECT rnd_id
+                 , alias
+                 , name
+                 , COALESCE(SUM(messages.count), 0)
+                 , COUNT(DISTINCT CASE
+                       WHEN messages.hour >= CAST(strftime('%s', 'now')
+                                                  AS INTEGER)/3600 - 30*24
+                       THEN messages.user_id
+                   END)
+                 , MAX(messages.hour)
+                 , chats_ava.doc IS NOT NULL
+              FROM chats
+              LEFT JOIN messages ON messages.chat_id = chats.id
+              LEFT JOIN chats_ava ON chats_ava.id = chats.id
+             WHERE {}
+             GROUP BY chats.id
+             ORDER BY {}
+             LIMIT :limit OFFSET :skip
+        ", filter, order).as_ref(),
+        args.as_slice(),
+        |row| {
+            result.chat_ids.push(row.get(0));
+            result.chat_aliases.push(row.get(1));
+            result.chat_names.push(row.get(2));
+            result.messages.push(row.get(3));
+            result.active_users.push(row.get(4));
+            result.last_hours.push(row.get(5));
+            result.chat_avatars.push(if row.get(6) {
+                Some(format!("/ava/{}.jpg", row.get::<_, String>(0)))
+            } else {
+                None
+            });
+        },
+    )?;
+
+    Ok(Ok(result))
+}
+
+#[derive(Debug, Serialize)]
+pub struct OverlapResult {
+    from: Option<String>,
+    to: Option<String>,
+    users: i64,
+
+    chat_ids: Vec<String>,
+    chat_aliases: Vec<Option<String>>,
+    chat_names: Vec<String>,
+    users_by_chat: Vec<i64>,
+    shared_users: Vec<i64>,
+    jaccard: Vec<f64>,
+}
+
+pub fn overlap_http(
+    conn: &Connection,
+    chat: &str,
+    dates: Option<(i64, i64)>,
+    offset: i64,
+    limit: i64,
+) -> (u16, String) {
+    db_util::http(overlap(conn, chat, dates, offset, limit))
+}
+
+/// Other chats sharing active posters with `chat`, most similar first.
+pub fn overlap(
+    conn: &Connection,
+    chat: &str,
+    dates: Option<(i64, i64)>,
+    offset: i64,
+    limit: i64,
+) -> Result<(u16, String), MyError> {
+    if let Err((status, err)) = db::Params::range(dates, offset).check() {
+        return Ok((status, String::from(err)));
+    }
+
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Ok((400, String::from(ERR_INVALID_LIMIT)));
+    }
+
+    let (chat_id, _) = match db::search_chat(conn, chat) {
+        Some(x) => x,
+        None => return Ok((404, String::from(db::ERR_CHAT_NOT_FOUND))),
+    };
+
+    let mut result = OverlapResult {
+        from: dates.map(|x| date::format_day(x.0)),
+        to: dates.map(|x| date::format_day(x.1)),
+        users: 0,
+
+        chat_ids: Vec::new(),
+        chat_aliases: Vec::new(),
+        chat_names: Vec::new(),
+        users_by_chat: Vec::new(),
+        shared_users: Vec::new(),
+        jaccard: Vec::new(),
+    };
+
+    let hours = dates.map(|(a, b)| (a*24 - offset, b*24 - offset + 23));
+    let mut args: Vec<(&str, &dyn ToSql)> = Vec::new();
+    args.push((":chat_id", &chat_id));
+    let mut filter = "";
+    if let Some(hours) = hours.as_ref() {
+        filter = "WHERE hour BETWEEN :hour_from AND :hour_to";
+        args.push((":hour_from", &hours.0));
+        args.push((":hour_to", &hours.1));
+    }
+
+    let mut rows = Vec::new();
+    db_util::query_map_named(
+        conn,
+        format!("
+            WITH active AS (
+                SELECT DISTINCT chat_id, user_id
+                  FROM messages
+                       {}
+            ), totals AS (
+                SELECT chat_id, COUNT(*) AS users
+                  FROM active
+                 GROUP BY chat_id
+            )
+            SELECT other.chat_id
+                 , chats.rnd_id
+                 , chats.alias
+                 , chats.name
+                 , totals.users
+                 , COUNT(*)
+              FROM active AS mine
+             INNER JOIN active AS other ON other.user_id = mine.user_id
+             INNER JOIN totals ON totals.chat_id = other.chat_id
+             INNER JOIN chats ON chats.id = other.chat_id
+             WHERE mine.chat_id = :chat_id
+               AND (other.chat_id = :chat_id
+                    OR other.chat_id NOT IN (SELECT id FROM chats_unlisted))
+             GROUP BY other.chat_id
+        ", filter).as_ref(),
+        args.as_slice(),
+        |row| {
+            if row.get::<_, i64>(0) == chat_id {
+                result.users = row.get(4);
+                return;
+            }
+            let rnd_id: String = row.get(1);
+            let alias: Option<String> = row.get(2);
+            let name: String = row.get(3);
+            let users: i64 = row.get(4);
+            let shared: i64 = row.get(5);
+            rows.push((rnd_id, alias, name, users, shared));
+        },
+    )?;
+
+    let mut rows: Vec<_> = rows
+        .into_iter()
+        .map(|x| {
+            let union = result.users + x.3 - x.4;
+            (x.4 as f64 / union as f64, x)
+        })
+        .collect();
+    rows.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+
+    for (jaccard, row) in rows.into_iter().take(limit as usize) {
+        result.chat_ids.push(row.0);
+        result.chat_aliases.push(row.1);
+        result.chat_names.push(row.2);
+        result.users_by_chat.push(row.3);
+        result.shared_users.push(row.4);
+        result.jaccard.push(jaccard);
+    }
+
+    Ok((200, serde_json::to_string(&result).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_page_overflow() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../scripts/init.sql")).unwrap();
+        let r = list(&conn, "", None, i64::MAX, 10).unwrap();
+        assert_eq!(r.err(), Some((400, ERR_INVALID_PAGE)));
+        let r = list(&conn, "", None, i64::MAX / 10, 10).unwrap().unwrap();
+        assert_eq!(r.total, 0);
+    }
+}
@@ -0,0 +1,166 @@
+use rusqlite::Connection;
+use rusqlite::types::ToSql;
+use super::db::{self, Filter, Params, Reply};
+use super::db_util;
+use super::error::MyError;
+
+pub const MAX_CHATS: usize = 10;
+
+const ERR_INVALID_CHATS: &str = r#"{"error":"invalid chats"}"#;
+
+#[derive(Debug, Serialize)]
+pub struct CompareResult {
+    from: Option<String>,
+    to: Option<String>,
+
+    bucket: &'static str,
+    bucket_starts: Vec<String>,
+    start_day: i64,
+    skip_day: i64,
+
+    // One entry per requested chat, in the requested order
+    titles: Vec<String>,
+    daily_users: Vec<Vec<i64>>,
+    daily_messages: Vec<Vec<i64>>,
+    messages_by_hour: Vec<[i64; 24]>,
+    messages_by_weekday: Vec<[i64; 7]>,
+
+    // shared_users[i][j]: users who posted in both chats i and j.
+    // The diagonal holds the number of users of each chat.
+    shared_users: Vec<Vec<i64>>,
+}
+
+pub fn query_http(
+    conn: &Connection,
+    chats: &[&str],
+    p: &Params,
+) -> (u16, String) {
+    db_util::http(query(conn, chats, p))
+}
+
+pub fn query(
+    conn: &Connection,
+    chats: &[&str],
+    p: &Params,
+) -> Result<(u16, String), MyError> {
+    Ok(db::reply_json(compare(conn, chats, p)?))
+}
+
+fn compare(
+    conn: &Connection,
+    chats: &[&str],
+    p: &Params,
+) -> Result<Reply<CompareResult>, MyError> {
+    if chats.is_empty() || chats.len() > MAX_CHATS {
+        return Ok(Err((400, ERR_INVALID_CHATS)));
+    }
+
+    let mut chat_ids = Vec::new();
+    for chat in chats.iter() {
+        match db::search_chat(conn, chat) {
+            Some((id, _)) => chat_ids.push(id),
+            None => return Ok(Err((404, db::ERR_CHAT_NOT_FOUND))),
+        }
+    }
+    let ids = chat_ids
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    if let Err(e) = p.check() {
+        return Ok(Err(e));
+    }
+
+    // Without an explicit range every chat would start at its own first
+    // day, so pick the range covering all of them.
+    let mut p = *p;
+    if p.dates.is_none() {
+        p.dates = conn.query_row_named(
+            format!("
+                SELECT (MIN(hour) + :offset)/24, (MAX(hour) + :offset)/24
+                  FROM messages
+                 WHERE chat_id IN ({})
+            ", ids).as_ref(),
+            &[(":offset", &p.offset)],
+            |row| match (row.get(0), row.get(1)) {
+                (Some(from), Some(to)) => Some(p.clamp_dates((from, to))),
+                _ => None,
+            },
+        )?;
+    }
+
+    let filter = match Filter::new(conn, &p)? {
+        Ok(x) => x,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    let mut result = CompareResult {
+        from: None,
+        to: None,
+
+        bucket: p.bucket.name(),
+        bucket_starts: Vec::new(),
+        start_day: 0,
+        skip_day: 1,
+
+        titles: Vec::new(),
+        daily_users: Vec::new(),
+        daily_messages: Vec::new(),
+        messages_by_hour: Vec::new(),
+        messages_by_weekday: Vec::new(),
+
+        shared_users: vec![vec![0; chats.len()]; chats.len()],
+    };
+
+    for chat in chats.iter() {
+        let stats = match db::stats(conn, chat, &p)? {
+            Ok(x) => x,
+            Err(e) => return Ok(Err(e)),
+        };
+        result.from = stats.from;
+        result.to = stats.to;
+        result.bucket_starts = stats.bucket_starts;
+        result.start_day = stats.start_day;
+        result.skip_day = stats.skip_day;
+
+        result.titles.push(stats.title);
+        result.daily_users.push(stats.daily_users);
+        result.daily_messages.push(stats.daily_messages);
+        result.messages_by_hour.push(stats.messages_by_hour);
+        result.messages_by_weekday.push(stats.messages_by_weekday);
+    }
+
+    let mut args: Vec<(&str, &dyn ToSql)> = Vec::new();
+    filter.args(&mut args);
+    db_util::query_map_named(
+        conn,
+        format!("
+            WITH active AS (
+                SELECT DISTINCT chat_id, user_id
+                  FROM messages
+                 WHERE chat_id IN ({})
+                       {}
+            )
+            SELECT a.chat_id, b.chat_id, COUNT(*)
+              FROM active AS a
+             INNER JOIN active AS b ON a.user_id = b.user_id
+             GROUP BY a.chat_id, b.chat_id
+        ", ids, filter.sql).as_ref(),
+        args.as_slice(),
+        |row| {
+            let a: i64 = row.get(0);
+            let b: i64 = row.get(1);
+            let count = row.get(2);
+            for (i, &x) in chat_ids.iter().enumerate() {
+                for (j, &y) in chat_ids.iter().enumerate() {
+                    if x == a && y == b {
+                        result.shared_users[i][j] = count;
+                    }
+                }
+            }
+        },
+    )?;
+
+    Ok(Ok(result))
+}
@@ -0,0 +1,223 @@
+use rusqlite::Connection;
+use rusqlite::types::ToSql;
+use std::cmp::Reverse;
+use super::date;
+use super::db;
+use super::db_util;
+use super::error::MyError;
+use super::metrics;
+use super::serde_json;
+
+#[derive(Debug, Serialize)]
+pub struct RecordsResult {
+    title: String,
+
+    // (local date or time, messages)
+    busiest_day: Option<(String, i64)>,
+    busiest_hour: Option<(String, i64)>,
+    busiest_week: Option<(String, i64)>,
+
+    // (first day, length in days)
+    longest_streak: Option<(String, i64)>,
+    current_streak: i64,
+
+    // Every user, longest streak first
+    user_ids: Vec<String>,
+    user_names: Vec<String>,
+    longest_streaks: Vec<(String, i64)>,
+    current_streaks: Vec<i64>,
+
+    // Top poster of each calendar year
+    years: Vec<i64>,
+    top_user_ids: Vec<String>,
+    top_user_names: Vec<String>,
+    top_user_messages: Vec<i64>,
+}
+
+pub fn query_http(
+    conn: &Connection,
+    chat: &str,
+    offset: i64,
+) -> (u16, String) {
+    db_util::http(query(conn, chat, offset))
+}
+
+pub fn query(
+    conn: &Connection,
+    chat: &str,
+    offset: i64,
+) -> Result<(u16, String), MyError> {
+    if !(-12..=12).contains(&offset) {
+        return Ok((400, String::from(db::ERR_INVALID_OFFSET)));
+    }
+
+    let (chat_id, chat_title) = match db::search_chat(conn, chat) {
+        Some(x) => x,
+        None => return Ok((404, String::from(db::ERR_CHAT_NOT_FOUND))),
+    };
+
+    let mut result = RecordsResult {
+        title: chat_title,
+
+        busiest_day: None,
+        busiest_hour: None,
+        busiest_week: None,
+
+        longest_streak: None,
+        current_streak: 0,
+
+        user_ids: Vec::new(),
+        user_names: Vec::new(),
+        longest_streaks: Vec::new(),
+        current_streaks: Vec::new(),
+
+        years: Vec::new(),
+        top_user_ids: Vec::new(),
+        top_user_names: Vec::new(),
+        top_user_messages: Vec::new(),
+    };
+
+    let args: &[(&str, &dyn ToSql)] = &[
+        (":chat_id", &chat_id),
+        (":offset", &offset),
+    ];
+
+    result.busiest_day = db_util::query_row_named(
+        conn,
+        "
+            SELECT (hour + :offset)/24, SUM(count)
+              FROM messages
+             WHERE chat_id = :chat_id
+             GROUP BY 1
+             ORDER BY 2 DESC, 1
+             LIMIT 1
+        ",
+        args,
+        |row| (date::format_day(row.get(0)), row.get(1)),
+    )?;
+
+    result.busiest_hour = db_util::query_row_named(
+        conn,
+        "
+            SELECT hour + :offset, SUM(count)
+              FROM messages
+             WHERE chat_id = :chat_id
+             GROUP BY 1
+             ORDER BY 2 DESC, 1
+             LIMIT 1
+        ",
+        args,
+        |row| {
+            let hour: i64 = row.get(0);
+            let time = format!(
+                "{} {:02}:00",
+                date::format_day(hour / 24),
+                hour % 24,
+            );
+            (time, row.get(1))
+        },
+    )?;
+
+    result.busiest_week = db_util::query_row_named(
+        conn,
+        "
+            SELECT (hour + :offset)/24 - ((hour + :offset)/24 + 3)%7
+                 , SUM(count)
+              FROM messages
+             WHERE chat_id = :chat_id
+             GROUP BY 1
+             ORDER BY 2 DESC, 1
+             LIMIT 1
+        ",
+        args,
+        |row| (date::format_day(row.get(0)), row.get(1)),
+    )?;
+
+    let today = date::today(offset);
+
+    let mut days = Vec::new();
+    db_util::query_map_named(
+        conn,
+        "
+            SELECT DISTINCT (hour + :offset)/24
+              FROM messages
+             WHERE chat_id = :chat_id
+             ORDER BY 1
+        ",
+        args,
+        |row| days.push(row.get(0)),
+    )?;
+    if !days.is_empty() {
+        let (start, length) = metrics::longest_streak(&days);
+        result.longest_streak = Some((date::format_day(start), length));
+        result.current_streak = metrics::current_streak(&days, today);
+    }
+
+    let mut users: Vec<(String, String, Vec<i64>)> = Vec::new();
+    db_util::query_map_named(
+        conn,
+        "
+            SELECT DISTINCT users.rnd_id
+                 , users.name
+                 , (messages.hour + :offset)/24
+              FROM messages
+             INNER JOIN users ON users.id = messages.user_id
+             WHERE messages.chat_id = :chat_id
+             ORDER BY 1, 3
+        ",
+        args,
+        |row| {
+            let rnd_id: String = row.get(0);
+            let day: i64 = row.get(2);
+            if users.last().is_some_and(|x| x.0 == rnd_id) {
+                users.last_mut().unwrap().2.push(day);
+            } else {
+                users.push((rnd_id, row.get(1), vec![day]));
+            }
+        },
+    )?;
+    let mut users: Vec<_> = users
+        .into_iter()
+        .map(|(rnd_id, name, days)| {
+            let longest = metrics::longest_streak(&days);
+            let current = metrics::current_streak(&days, today);
+            (rnd_id, name, longest, current)
+        })
+        .collect();
+    users.sort_by_key(|x| Reverse((x.2).1));
+    for (rnd_id, name, (start, length), current) in users.into_iter() {
+        result.user_ids.push(rnd_id);
+        result.user_names.push(name);
+        result.longest_streaks.push((date::format_day(start), length));
+        result.current_streaks.push(current);
+    }
+
+    // Relies on SQLite picking the bare columns from the row with MAX().
+    db_util::query_map_named(
+        conn,
+        "
+            SELECT year, users.rnd_id, users.name, MAX(messages)
+              FROM (
+                    SELECT CAST(strftime('%Y', (hour + :offset)*3600,
+                                         'unixepoch') AS INTEGER) AS year
+                         , user_id
+                         , SUM(count) AS messages
+                      FROM messages
+                     WHERE chat_id = :chat_id
+                     GROUP BY 1, 2
+                   )
+             INNER JOIN users ON users.id = user_id
+             GROUP BY year
+             ORDER BY year
+        ",
+        args,
+        |row| {
+            result.years.push(row.get(0));
+            result.top_user_ids.push(row.get(1));
+            result.top_user_names.push(row.get(2));
+            result.top_user_messages.push(row.get(3));
+        },
+    )?;
+
+    Ok((200, serde_json::to_string(&result).unwrap()))
+}
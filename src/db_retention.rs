@@ -0,0 +1,196 @@
+use rusqlite::Connection;
+use rusqlite::types::ToSql;
+use std::collections::HashMap;
+use super::date::{self, Bucket};
+use super::db;
+use super::db_util;
+use super::error::MyError;
+use super::serde_json;
+
+pub const MAX_WEEKS: i64 = 52;
+
+const ERR_INVALID_WEEKS: &str = r#"{"error":"invalid weeks"}"#;
+
+#[derive(Debug, Serialize)]
+pub struct RetentionResult {
+    title: String,
+
+    // Users by the day of their first message in the chat
+    start_day: i64,
+    new_users: Vec<i64>,
+
+    // Same, by week (weeks start on Monday, but the first one at
+    // `start_day`)
+    week_starts: Vec<String>,
+    new_users_weekly: Vec<i64>,
+
+    // retention[i][n]: share of users first seen in week i who also posted
+    // n weeks later. Rows are cut at the last week with data.
+    retention: Vec<Vec<f64>>,
+}
+
+pub fn query_http(
+    conn: &Connection,
+    chat: &str,
+    dates: Option<(i64, i64)>,
+    offset: i64,
+    weeks: i64,
+) -> (u16, String) {
+    db_util::http(query(conn, chat, dates, offset, weeks))
+}
+
+pub fn query(
+    conn: &Connection,
+    chat: &str,
+    dates: Option<(i64, i64)>,
+    offset: i64,
+    weeks: i64,
+) -> Result<(u16, String), MyError> {
+    if let Err((status, err)) = db::Params::range(dates, offset).check() {
+        return Ok((status, String::from(err)));
+    }
+
+    if !(1..=MAX_WEEKS).contains(&weeks) {
+        return Ok((400, String::from(ERR_INVALID_WEEKS)));
+    }
+
+    let (chat_id, chat_title) = match db::search_chat(conn, chat) {
+        Some(x) => x,
+        None => return Ok((404, String::from(db::ERR_CHAT_NOT_FOUND))),
+    };
+
+    let mut result = RetentionResult {
+        title: chat_title,
+
+        start_day: 0,
+        new_users: Vec::new(),
+
+        week_starts: Vec::new(),
+        new_users_weekly: Vec::new(),
+
+        retention: Vec::new(),
+    };
+
+    let args: &[(&str, &dyn ToSql)] = &[
+        (":chat_id", &chat_id),
+        (":offset", &offset),
+    ];
+
+    let mut first_days = Vec::new();
+    db_util::query_map_named(
+        conn,
+        "
+            SELECT (MIN(hour) + :offset)/24
+              FROM messages
+             WHERE chat_id = :chat_id
+             GROUP BY user_id
+             ORDER BY 1
+        ",
+        args,
+        |row| first_days.push(row.get::<_, i64>(0)),
+    )?;
+
+    // (cohort week, active week) -> users
+    let mut active = HashMap::new();
+    let mut last_week = 0;
+    db_util::query_map_named(
+        conn,
+        "
+            WITH firsts AS (
+                SELECT user_id, (MIN(hour) + :offset)/24 AS day
+                  FROM messages
+                 WHERE chat_id = :chat_id
+                 GROUP BY user_id
+            ), weeks AS (
+                SELECT DISTINCT user_id
+                     , (hour + :offset)/24 - ((hour + :offset)/24 + 3)%7
+                       AS week
+                  FROM messages
+                 WHERE chat_id = :chat_id
+            )
+            SELECT firsts.day - (firsts.day + 3)%7, weeks.week, COUNT(*)
+              FROM firsts
+             INNER JOIN weeks ON weeks.user_id = firsts.user_id
+             GROUP BY 1, 2
+        ",
+        args,
+        |row| {
+            let cohort: i64 = row.get(0);
+            let week: i64 = row.get(1);
+            let users: i64 = row.get(2);
+            active.insert((cohort, week), users);
+            last_week = last_week.max(week);
+        },
+    )?;
+
+    let (from, to) = match (dates, first_days.first(), first_days.last()) {
+        (Some(dates), _, _) => dates,
+        (None, Some(&from), Some(&to)) => (from, to),
+        _ => return Ok((200, serde_json::to_string(&result).unwrap())),
+    };
+
+    result.start_day = from;
+    result.new_users = vec![0; (to - from + 1) as usize];
+    for &day in first_days.iter() {
+        if day >= from && day <= to {
+            result.new_users[(day - from) as usize] += 1;
+        }
+    }
+
+    let mut week = Bucket::Week.start(from);
+    while week <= to {
+        let size = *active.get(&(week, week)).unwrap_or(&0);
+        let mut row = Vec::new();
+        let mut n = 0;
+        while n <= weeks && week + n*7 <= last_week {
+            let users = *active.get(&(week, week + n*7)).unwrap_or(&0);
+            row.push(if size == 0 { 0.0 } else { users as f64 / size as f64 });
+            n += 1;
+        }
+        let (first, last) = (week.max(from) - from, (week + 6).min(to) - from);
+        result.week_starts.push(date::format_day(week.max(from)));
+        result.new_users_weekly.push(
+            result.new_users[first as usize..=last as usize].iter().sum(),
+        );
+        result.retention.push(row);
+        week = Bucket::Week.next(week);
+    }
+
+    Ok((200, serde_json::to_string(&result).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn weekly_new_users_in_range() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../scripts/init.sql")).unwrap();
+        // 2018-01-01 is a Monday, day 17532
+        conn.execute_batch("
+            INSERT INTO chats VALUES (1, 0, 1, 'c1', 'Chat', NULL);
+            INSERT INTO messages VALUES (1, 1, 17532*24, 1);
+            INSERT INTO messages VALUES (1, 2, 17534*24, 1);
+            INSERT INTO messages VALUES (1, 3, 17540*24, 1);
+            INSERT INTO messages VALUES (1, 4, 17547*24, 1);
+        ").unwrap();
+
+        let (status, text) =
+            query(&conn, "c1", Some((17533, 17545)), 0, 4).unwrap();
+        assert_eq!(status, 200);
+        let r: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(
+            r["week_starts"].to_string(),
+            r#"["2018-01-02","2018-01-08"]"#,
+        );
+        assert_eq!(r["new_users_weekly"].to_string(), "[1,1]");
+        assert_eq!(r["new_users"].as_array().unwrap().len(), 13);
+
+        let (_, text) = query(&conn, "c1", None, 0, 4).unwrap();
+        let r: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(r["week_starts"][0], "2018-01-01");
+        assert_eq!(r["new_users_weekly"].to_string(), "[2,1,1]");
+    }
+}
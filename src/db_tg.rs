@@ -14,7 +14,7 @@ use telegram_bot_raw::{
 };
 
 pub fn update_from_file(conn: &mut Connection, path: &str) {
-    let err = process_log::process_log(path, &mut DbTg { conn: conn });
+    let err = process_log::process_log(path, &mut DbTg { conn });
     eprintln!("err = {:?}", err);
 }
 
@@ -192,7 +192,7 @@ impl<'a> process_log::LogProcessor for DbTg<'a> {
         self.conn.execute("ROLLBACK", &[])?;
         Ok(())
     }
-    fn process_line(&mut self, line: &String) -> Result<(), Self::Error> {
+    fn process_line(&mut self, line: &str) -> Result<(), Self::Error> {
         match serde_json::from_str::<Update>(line) {
             Ok(upd) => Ok(update(self.conn, upd)?),
             Err(err) => {
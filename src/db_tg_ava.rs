@@ -1,44 +1,171 @@
-use hyper::rt::Future;
-use reqwest;
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream};
+use reqwest::{self, StatusCode};
+use reqwest::header::ContentType;
+use reqwest::unstable::async::{Client, Response};
 use rusqlite::Connection;
-use std::fs::{File, remove_file};
-use std::io::copy;
+use std::cell::Cell;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use super::ava;
+use super::db_chat_ava;
 use super::db_util;
 use super::error::MyError;
 use telegram_bot::types::requests::{GetUserProfilePhotos, GetFile};
 use telegram_bot::types::{UserId, PhotoSize};
-use telegram_bot::{Api, ErrorKind};
-use telegram_bot_raw;
-use tokio_core::reactor::Core;
+use telegram_bot_raw::{
+    self,
+    Body,
+    ErrorKind,
+    HttpResponse,
+    Method,
+    Request,
+    RequestUrl,
+    ResponseType,
+    TELEGRAM_URL,
+};
+use tokio_core::reactor::{Core, Handle, Timeout};
 
+/// Settings of `sync-tg-ava`, given as command line flags.
 #[derive(Debug)]
-struct Row {
-    id:      i64,
-    rnd_id: String,
-    tg_id:   i64,
-    old:     Option<String>,
+pub struct Options {
+    // Only check users with messages in this many days, 0 for all
+    pub active_days: i64,
+    // Keep checking synced users past `active_days`
+    pub keep_synced: bool,
+    // Most Bot API requests and downloads per run, retries included
+    pub budget: Option<u64>,
+    // Only report what would change
+    pub dry_run: bool,
+    // Requests and downloads in flight at once
+    pub concurrency: usize,
+    // Bot API server, e.g. a self-hosted telegram-bot-api
+    pub api_url: String,
+    // Prefix of file paths from getFile, `<api_url>file/bot<token>/` if
+    // not given
+    pub file_url: Option<String>,
+    // Where a server started with --local keeps its files, the only place
+    // absolute file paths are read from
+    pub local_files: Option<PathBuf>,
 }
 
-fn db_get_rows(conn: &mut Connection) -> Result<Vec<Row>, MyError> {
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            active_days: 90,
+            keep_synced: false,
+            budget: None,
+            dry_run: false,
+            concurrency: 8,
+            api_url: String::from(TELEGRAM_URL),
+            file_url: None,
+            local_files: None,
+        }
+    }
+}
+
+fn with_slash(url: &str) -> String {
+    if url.ends_with('/') {
+        String::from(url)
+    } else {
+        format!("{}/", url)
+    }
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut opts = Options::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next().ok_or_else(|| format!("{} needs a value", arg))
+            };
+            match arg.as_ref() {
+                "--active-days" => {
+                    opts.active_days = value()?
+                        .parse()
+                        .map_err(|_| format!("Invalid {}", arg))?;
+                }
+                "--budget" => {
+                    opts.budget = Some(value()?
+                        .parse()
+                        .map_err(|_| format!("Invalid {}", arg))?);
+                }
+                "--keep-synced" => opts.keep_synced = true,
+                "--dry-run" => opts.dry_run = true,
+                "--concurrency" => {
+                    opts.concurrency = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&x| x > 0)
+                        .ok_or_else(|| format!("Invalid {}", arg))?;
+                }
+                "--api-url" => opts.api_url = with_slash(value()?),
+                "--file-url" => opts.file_url = Some(with_slash(value()?)),
+                "--local-files" => {
+                    opts.local_files = Some(fs::canonicalize(value()?)
+                        .map_err(|e| format!("Invalid {}: {}", arg, e))?);
+                }
+                _ => return Err(format!("Unknown option {}", arg)),
+            }
+        }
+        Ok(opts)
+    }
+
+    fn file_url(&self, token: &str) -> String {
+        match self.file_url {
+            Some(ref x) => x.clone(),
+            None => format!("{}file/bot{}/", self.api_url, token),
+        }
+    }
+}
+
+/// Telegram users due for a check, most recently active first. Users are
+/// checked at most daily, and only if they posted within
+/// `Options::active_days` unless already synced and `keep_synced` is set.
+fn db_get_rows(
+    conn: &mut Connection,
+    opts: &Options,
+) -> Result<Vec<(Target, i64)>, MyError> {
     let mut rows = Vec::new();
     db_util::query_map_named(
         conn,
         "
             SELECT users.id, users.rnd_id, users.ext_id, users_tg.doc
-              FROM users_tg
-             LEFT JOIN users
-                    ON users.id = users_tg.id
-             WHERE users_tg.last_upd + 60*60*24
-                 < CAST(strftime('%s', 'now') AS INTEGER);
+              FROM users
+              LEFT JOIN (SELECT user_id, MAX(hour) AS last_hour
+                           FROM messages
+                          GROUP BY user_id) AS activity
+                     ON activity.user_id = users.id
+              LEFT JOIN users_tg
+                     ON users_tg.id = users.id
+             WHERE users.kind = 0
+               AND (users_tg.id IS NULL
+                    OR users_tg.last_upd + 60*60*24
+                     < CAST(strftime('%s', 'now') AS INTEGER))
+               AND (:active_days = 0
+                    OR activity.last_hour
+                     > CAST(strftime('%s', 'now') AS INTEGER)/3600
+                       - :active_days*24
+                    OR (:keep_synced AND users_tg.id IS NOT NULL))
+             ORDER BY activity.last_hour DESC
         ",
-        &[],
+        &[
+            (":active_days", &opts.active_days),
+            (":keep_synced", &opts.keep_synced),
+        ],
         |row| {
-            rows.push(Row {
-                id:     row.get(0),
-                rnd_id: row.get(1),
-                tg_id:  row.get(2),
-                old:    row.get(3),
-            })
+            rows.push((
+                Target {
+                    id:     row.get(0),
+                    rnd_id: row.get(1),
+                    old:    row.get(3),
+                },
+                row.get(2),
+            ))
         },
     )?;
     println!("Done");
@@ -46,7 +173,7 @@ fn db_get_rows(conn: &mut Connection) -> Result<Vec<Row>, MyError> {
 }
 
 fn db_set_have(
-    conn: &mut Connection,
+    conn: &Connection,
     id: i64,
     doc: &Option<String>,
 ) -> Result<(), MyError> {
@@ -60,94 +187,805 @@ fn db_set_have(
     Ok(())
 }
 
-fn get_file(file_id: String) -> GetFile {
+pub fn get_file(file_id: String) -> GetFile {
     GetFile::new(PhotoSize {
-        file_id: file_id,
+        file_id,
         width: 0,
         height: 0,
         file_size: None
     })
 }
 
-fn save_to_file(
-    token: &str,
-    file_path: &str,
-    save_path: &str,
-) -> Result<(), MyError> {
-    let url =
-        format!("https://api.telegram.org/file/bot{}/{}", token, file_path);
-    let mut resp = reqwest::get(url.as_str())?;
-    assert!(resp.status().is_success());
-
-    let out = format!("./ava/{}.jpg", save_path);
-    let mut file = File::create(out)?;
-    copy(&mut resp, &mut file)?;
-    Ok(())
+/// Why a request failed, deciding whether to retry it.
+#[derive(Debug)]
+pub enum Failure {
+    // Flood control, seconds to wait
+    RateLimit(u64),
+    // The user or chat is gone, same as having no photo
+    NotFound(String),
+    // Network and server errors, worth retrying
+    Transient(String),
+    // Anything else, retried on the next run
+    Other(String),
+    // The request budget of this run is spent
+    Budget,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::RateLimit(secs) => write!(f, "retry after {}s", secs),
+            Failure::NotFound(ref e)
+            | Failure::Transient(ref e)
+            | Failure::Other(ref e) => write!(f, "{}", e),
+            Failure::Budget => write!(f, "request budget spent"),
+        }
+    }
+}
+
+impl From<MyError> for Failure {
+    fn from(e: MyError) -> Self {
+        Failure::Other(e.to_string())
+    }
+}
+
+const NOT_FOUND: &[&str] = &[
+    "user not found",
+    "chat not found",
+    "user_id_invalid",
+    "peer_id_invalid",
+    "user is deactivated",
+];
+
+const MAX_RETRIES: u32 = 5;
+const CHUNK: usize = 100;
+const BACKOFF_MS: u64 = 1000;
+
+fn classify(e: &telegram_bot_raw::Error, status: StatusCode) -> Failure {
+    let transient = status == StatusCode::TooManyRequests
+        || status.is_server_error();
+    match *e.kind() {
+        ErrorKind::TelegramError {
+            ref description,
+            ref parameters,
+        } => {
+            let retry_after = parameters.as_ref().and_then(|x| x.retry_after);
+            let lower = description.to_lowercase();
+            if let Some(secs) = retry_after {
+                Failure::RateLimit(secs.max(0) as u64)
+            } else if NOT_FOUND.iter().any(|x| lower.contains(x)) {
+                Failure::NotFound(description.clone())
+            } else if transient {
+                Failure::Transient(description.clone())
+            } else {
+                Failure::Other(description.clone())
+            }
+        }
+        // Not a Bot API reply, e.g. an error page of a proxy
+        _ if transient => Failure::Transient(status.to_string()),
+        ref e => Failure::Other(format!("{:?}", e)),
+    }
+}
+
+pub type Pending<T> = Box<dyn Future<Item = T, Error = Failure>>;
+
+fn transient(e: reqwest::Error) -> Failure {
+    Failure::Transient(e.to_string())
 }
 
-fn yoba3 (
+fn read_body(resp: Response) -> Pending<Vec<u8>> {
+    Box::new(resp.into_body().map_err(transient).fold(
+        Vec::new(),
+        |mut data, chunk| {
+            data.extend_from_slice(&chunk);
+            Ok::<_, Failure>(data)
+        },
+    ))
+}
+
+/// Downloads `url` into memory.
+fn fetch(client: &Client, url: &str) -> Pending<Vec<u8>> {
+    Box::new(client.get(url).send().map_err(transient).and_then(
+        move |resp| -> Pending<Vec<u8>> {
+            let status = resp.status();
+            if status == StatusCode::TooManyRequests
+                || status.is_server_error()
+            {
+                return Box::new(future::err(
+                    Failure::Transient(status.to_string()),
+                ));
+            }
+            if !status.is_success() {
+                return Box::new(future::err(
+                    Failure::Other(status.to_string()),
+                ));
+            }
+            read_body(resp)
+        },
+    ))
+}
+
+/// Reads `path` if it is under `dir`, the `--local-files` directory. The
+/// files are small avatars, so they are read right on the event loop.
+fn read_local(dir: &Option<PathBuf>, path: &str) -> Result<Vec<u8>, Failure> {
+    let dir = match *dir {
+        Some(ref x) => x,
+        None => return Err(Failure::Other(
+            format!("{} is a local file, see --local-files", path),
+        )),
+    };
+    let path = fs::canonicalize(path)
+        .map_err(|e| Failure::Other(format!("{}: {}", path, e)))?;
+    if !path.starts_with(dir) {
+        return Err(Failure::Other(
+            format!("{} is outside --local-files", path.display()),
+        ));
+    }
+    fs::read(&path).map_err(|e| Failure::Other(e.to_string()))
+}
+
+/// Bot API and file download clients, cheap to clone into each job of the
+/// pipeline. Requests are counted against `Options::budget`.
+#[derive(Clone)]
+pub struct Bot {
+    client: Client,
+    handle: Handle,
+    api_url: String,
+    file_url: String,
+    local_files: Option<PathBuf>,
+    token: String,
+    budget: Option<u64>,
+    dry_run: bool,
+    requests: Rc<Cell<u64>>,
+    // No request is sent before this, as asked by flood control
+    not_before: Rc<Cell<Instant>>,
+}
+
+impl Bot {
+    /// Runs `f` until it succeeds or fails for good, backing off
+    /// exponentially. Flood control holds back every request of the bot,
+    /// not just the one that hit it.
+    fn retry<T, F, R>(&self, f: F) -> Pending<T>
+    where
+        T: 'static,
+        F: Fn() -> R + 'static,
+        R: Future<Item = T, Error = Failure> + 'static,
+    {
+        let bot = self.clone();
+        let f = Rc::new(f);
+        Box::new(future::loop_fn(0, move |attempt| -> Pending<Loop<T, u32>> {
+            let requests = &bot.requests;
+            if bot.budget.is_some_and(|x| requests.get() >= x) {
+                return Box::new(future::err(Failure::Budget));
+            }
+            requests.set(requests.get() + 1);
+
+            let bot = bot.clone();
+            let f = f.clone();
+            let wait = bot.sleep_until(bot.not_before.get());
+            Box::new(wait.and_then(move |()| {
+                f().then(move |res| -> Pending<Loop<T, u32>> {
+                    let backoff = BACKOFF_MS << attempt;
+                    let delay = match res {
+                        Ok(x) => return Box::new(future::ok(Loop::Break(x))),
+                        Err(Failure::RateLimit(secs))
+                            if attempt < MAX_RETRIES =>
+                        {
+                            let until =
+                                Instant::now() + Duration::from_secs(secs);
+                            if until > bot.not_before.get() {
+                                bot.not_before.set(until);
+                            }
+                            backoff
+                        }
+                        Err(Failure::Transient(ref e))
+                            if attempt < MAX_RETRIES =>
+                        {
+                            println!("Retrying: {}", e);
+                            backoff
+                        }
+                        Err(e) => return Box::new(future::err(e)),
+                    };
+                    let at = Instant::now() + Duration::from_millis(delay);
+                    Box::new(
+                        bot.sleep_until(at)
+                            .map(move |()| Loop::Continue(attempt + 1)),
+                    )
+                })
+            }))
+        }))
+    }
+
+    fn sleep_until(&self, at: Instant) -> Pending<()> {
+        Box::new(
+            future::result(Timeout::new_at(at, &self.handle))
+                .flatten()
+                .map_err(|e| Failure::Other(e.to_string())),
+        )
+    }
+
+    pub fn call<R>(
+        &self,
+        request: R,
+    ) -> Pending<<R::Response as ResponseType>::Type>
+    where
+        R: Request + Clone + 'static,
+    {
+        let bot = self.clone();
+        self.retry(move || bot.send(&request))
+    }
+
+    /// One Bot API request, like `telegram_bot::Api::send` but against
+    /// `Options::api_url`.
+    fn send<R>(
+        &self,
+        request: &R,
+    ) -> Pending<<R::Response as ResponseType>::Type>
+    where
+        R: Request,
+        <R::Response as ResponseType>::Type: 'static,
+    {
+        let http = match request.serialize() {
+            Ok(x) => x,
+            Err(e) => return Box::new(future::err(
+                Failure::Other(e.to_string()),
+            )),
+        };
+        let RequestUrl::Method(method) = http.url;
+        let url = format!("{}bot{}/{}", self.api_url, self.token, method);
+        let mut builder = match http.method {
+            Method::Get => self.client.get(&url),
+            Method::Post => self.client.post(&url),
+        };
+        if let Body::Json(body) = http.body {
+            builder.header(ContentType::json()).body(body);
+        }
+
+        Box::new(builder.send().map_err(transient).and_then(|resp| {
+            let status = resp.status();
+            read_body(resp).and_then(move |body| {
+                let resp = HttpResponse { body: Some(body) };
+                R::Response::deserialize(resp)
+                    .map_err(|e| classify(&e, status))
+            })
+        }))
+    }
+
+    /// Saves the photo `file_id` as the avatar of `rnd_id`. Thumbnails are
+    /// left to the caller, to keep `convert` off the event loop.
+    fn download(&self, file_id: String, rnd_id: String) -> Pending<()> {
+        let bot = self.clone();
+        Box::new(self.call(get_file(file_id)).and_then(
+            move |file| -> Pending<()> {
+                let file_path = match file.file_path {
+                    Some(x) => x,
+                    None => return Box::new(future::err(
+                        Failure::Other(String::from("no file_path")),
+                    )),
+                };
+                let save = move |data: Vec<u8>| {
+                    ava::save(&rnd_id, &data).map_err(Failure::from)
+                };
+                // A server started with --local gives paths on its disk
+                if file_path.starts_with('/') {
+                    return Box::new(future::result(
+                        read_local(&bot.local_files, &file_path)
+                            .and_then(save),
+                    ));
+                }
+                let url = format!("{}{}", bot.file_url, file_path);
+                let client = bot.client.clone();
+                Box::new(bot.retry(move || fetch(&client, &url)).and_then(save))
+            },
+        ))
+    }
+
+    /// Resolves `photo` to the current photo id, downloading it if it
+    /// differs from `old`. A missing user or chat has no photo.
+    pub fn sync(
+        &self,
+        photo: Pending<Option<String>>,
+        old: Option<String>,
+        rnd_id: String,
+    ) -> Pending<Option<String>> {
+        let bot = self.clone();
+        Box::new(
+            photo
+                .or_else(|e| match e {
+                    Failure::NotFound(description) => {
+                        println!("{:?}", description);
+                        Ok(None)
+                    }
+                    e => Err(e),
+                })
+                .and_then(move |new| -> Pending<Option<String>> {
+                    if new == old || bot.dry_run {
+                        return Box::new(future::ok(new));
+                    }
+                    match new {
+                        Some(id) => Box::new(
+                            bot.download(id.clone(), rnd_id)
+                                .map(move |()| Some(id)),
+                        ),
+                        None => Box::new(future::ok(None)),
+                    }
+                }),
+        )
+    }
+}
+
+/// Event loop of a `sync-tg-ava` run, shared by user and chat photos.
+pub struct Session<'a> {
+    core: Core,
+    pub bot: Bot,
+    opts: &'a Options,
+}
+
+impl<'a> Session<'a> {
+    pub fn new(token: &str, opts: &'a Options) -> Session<'a> {
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let bot = Bot {
+            client: Client::new(&handle),
+            handle,
+            api_url: opts.api_url.clone(),
+            file_url: opts.file_url(token),
+            local_files: opts.local_files.clone(),
+            token: String::from(token),
+            budget: opts.budget,
+            dry_run: opts.dry_run,
+            requests: Rc::new(Cell::new(0)),
+            not_before: Rc::new(Cell::new(Instant::now())),
+        };
+        Session {
+            core,
+            bot,
+            opts,
+        }
+    }
+}
+
+/// An avatar to check, with what was synced last time.
+pub struct Target {
+    pub id:     i64,
+    pub rnd_id: String,
+    pub old:    Option<String>,
+}
+
+/// Checks `rows` with up to `Options::concurrency` jobs in flight, `photo`
+/// giving the current photo of each. Results are written a chunk at a time
+/// in one transaction, then thumbnails are made for it. Returns false once
+/// the request budget is spent.
+pub fn run<T, F>(
     conn: &mut Connection,
-    core: &mut Core,
-    api: &Api,
-    token: &str,
-    row: &Row,
-) -> Result<(), MyError> {
-    let new =
-        core.run(
-            api.send(GetUserProfilePhotos::new(UserId::from(row.tg_id)).limit(1))
-        );
-    let new = match new {
-        Ok(x) => x,
-        Err(x) =>
-            match x.kind() {
-                ErrorKind::Raw(telegram_bot_raw::ErrorKind::TelegramError{description, parameters}) => {
-                    println!("{:?}", description);
-                    db_set_have(conn, row.id, &None)?;
-                    return Ok(())
+    s: &mut Session,
+    rows: Vec<(Target, T)>,
+    photo: F,
+    set_have: fn(&Connection, i64, &Option<String>) -> Result<(), MyError>,
+) -> Result<bool, MyError>
+where
+    F: Fn(&Bot, T) -> Pending<Option<String>>,
+{
+    let mut rows = rows.into_iter();
+    loop {
+        let chunk: Vec<_> = rows.by_ref().take(CHUNK).collect();
+        if chunk.is_empty() {
+            return Ok(true);
+        }
+
+        let bot = s.bot.clone();
+        let photo = &photo;
+        let jobs = stream::iter_ok::<_, ()>(chunk)
+            .map(|(target, x)| {
+                let job = bot.sync(
+                    photo(&bot, x),
+                    target.old.clone(),
+                    target.rnd_id.clone(),
+                );
+                job.then(move |res| Ok((target, res)))
+            })
+            .buffer_unordered(s.opts.concurrency)
+            .collect();
+        let results = s.core.run(jobs).unwrap();
+
+        let mut budget = true;
+        let mut changed = Vec::new();
+        let tx = conn.transaction()?;
+        for (target, res) in results {
+            let new = match res {
+                Ok(x) => x,
+                Err(Failure::Budget) => {
+                    budget = false;
+                    continue;
                 }
-                e => {
-                    println!("Error: {:?}", e);
-                    return Ok(());
+                Err(e) => {
+                    println!("{}: {}", target.rnd_id, e);
+                    continue;
                 }
-            },
-    };
-    let new = new
-        .photos.get(0)
-        .and_then(|x|x.last())
-        .map(|x|x.file_id.clone());
-
-    if new != row.old {
-        if let Some(ref new) = new {
-            let file_path =
-                core.run(
-                    api.send(get_file(new.to_string()))
-                    .map(|file| { file.file_path.unwrap() })
-                )?;
-            save_to_file(token, &file_path, &row.rnd_id)?;
-        } else {
-            println!("Removing {}", row.rnd_id);
-            remove_file(format!("./ava/{}.jpg", row.rnd_id))?;
+            };
+            if s.opts.dry_run {
+                if new != target.old {
+                    let action =
+                        if new.is_some() { "update" } else { "remove" };
+                    println!("Would {} {}", action, target.rnd_id);
+                }
+                continue;
+            }
+            set_have(&tx, target.id, &new)?;
+            if new != target.old {
+                changed.push((target.rnd_id, new.is_some()));
+            }
+        }
+        tx.commit()?;
+
+        // Files are only touched once the rows are saved, and a failure
+        // here doesn't undo them
+        for (rnd_id, have) in changed {
+            if have {
+                println!("Updated {}", rnd_id);
+                if let Err(e) = ava::make_thumbs(&rnd_id) {
+                    println!("Thumbnails for {}: {}", rnd_id, e);
+                }
+            } else {
+                println!("Removing {}", rnd_id);
+                if let Err(e) = ava::remove(&rnd_id) {
+                    println!("Removing {}: {}", rnd_id, e);
+                }
+            }
+        }
+
+        if !budget {
+            return Ok(false);
         }
-        db_set_have(conn, row.id, &new)?;
-    } else {
-        db_set_have(conn, row.id, &row.old)?;
     }
-    Ok(())
+}
+
+fn user_photo(bot: &Bot, tg_id: i64) -> Pending<Option<String>> {
+    let mut request = GetUserProfilePhotos::new(UserId::from(tg_id));
+    request.limit(1);
+    Box::new(bot.call(request).map(|x| {
+        x.photos.first()
+            .and_then(|x| x.last())
+            .map(|x| x.file_id.clone())
+    }))
 }
 
 pub fn update(
     conn: &mut Connection,
     token: &str,
+    opts: &Options,
 ) -> Result<(), MyError> {
-    let mut core = Core::new().unwrap();
-    let api = Api::configure(token).build(core.handle()).unwrap();
+    let _lock = ava::lock()?;
+    let mut s = Session::new(token, opts);
 
-    for row in db_get_rows(conn)?.iter() {
-        println!("{:?}", row);
-        if let Err(e) = yoba3(conn, &mut core, &api, token, row) {
-            println!("Err: {}", e);
-        }
+    let rows = db_get_rows(conn, opts)?;
+    if run(conn, &mut s, rows, user_photo, db_set_have)? {
+        db_chat_ava::update_tg(conn, &mut s)?;
     }
 
+    let requests = s.bot.requests.get();
+    println!("Requests: {}", requests);
+    if opts.budget.is_some_and(|x| requests >= x) {
+        println!("Request budget exhausted, the rest is left for next run");
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper;
+    use hyper::service::service_fn;
+    use serde_json::{self, Value};
+    use std::env;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    const TOKEN: &str = "123:abc";
+
+    fn args(x: &[&str]) -> Vec<String> {
+        x.iter().map(|x| String::from(*x)).collect()
+    }
+
+    #[test]
+    fn options_urls() {
+        let opts = Options::parse(&[]).unwrap();
+        assert_eq!(
+            opts.file_url("123:abc"),
+            "https://api.telegram.org/file/bot123:abc/",
+        );
+
+        let opts = Options::parse(&args(&[
+            "--api-url", "http://127.0.0.1:8081",
+        ])).unwrap();
+        assert_eq!(opts.api_url, "http://127.0.0.1:8081/");
+        assert_eq!(
+            opts.file_url("123:abc"),
+            "http://127.0.0.1:8081/file/bot123:abc/",
+        );
+
+        let opts = Options::parse(&args(&[
+            "--api-url", "http://127.0.0.1:8081/",
+            "--file-url", "http://127.0.0.1:8082/files",
+        ])).unwrap();
+        assert_eq!(opts.file_url("123:abc"), "http://127.0.0.1:8082/files/");
+    }
+
+    #[test]
+    fn rows_skip_inactive_users() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../scripts/init.sql")).unwrap();
+        conn.execute_batch("
+            INSERT INTO chats VALUES (1, 0, 1, 'c1', 'Chat', NULL);
+            INSERT INTO users VALUES (1, 0, 11, 'u1', 'Active');
+            INSERT INTO users VALUES (2, 0, 12, 'u2', 'Dormant, synced');
+            INSERT INTO users VALUES (3, 0, 13, 'u3', 'Dormant');
+            INSERT INTO users VALUES (4, 0, 14, 'u4', 'Active, fresh');
+            INSERT INTO messages
+            SELECT 1, id, CAST(strftime('%s', 'now') AS INTEGER)/3600
+                          - CASE WHEN id IN (1, 4) THEN 24 ELSE 24*200 END
+                 , 1
+              FROM users;
+            INSERT INTO users_tg VALUES (2, 0, NULL);
+            INSERT INTO users_tg VALUES (4, strftime('%s', 'now'), NULL);
+        ").unwrap();
+
+        let mut ids = |opts: &Options| -> Vec<i64> {
+            let mut ids: Vec<i64> = db_get_rows(&mut conn, opts)
+                .unwrap()
+                .iter()
+                .map(|x| x.0.id)
+                .collect();
+            ids.sort();
+            ids
+        };
+        let mut opts = Options::default();
+        assert_eq!(ids(&opts), vec![1]);
+        opts.keep_synced = true;
+        assert_eq!(ids(&opts), vec![1, 2]);
+        opts.keep_synced = false;
+        opts.active_days = 0;
+        assert_eq!(ids(&opts), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn options_invalid() {
+        assert!(Options::parse(&args(&["--api-url"])).is_err());
+        assert!(Options::parse(&args(&["--concurrency", "0"])).is_err());
+        assert!(Options::parse(&args(&["--local-files", "/nonexistent"]))
+            .is_err());
+        assert!(Options::parse(&args(&["--bogus"])).is_err());
+    }
+
+    #[test]
+    fn local_files() {
+        let root = env::temp_dir()
+            .join(format!("tg-ava-test-{}", std::process::id()));
+        let dir = root.join("files");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("photo.jpg"), b"photo").unwrap();
+        fs::write(root.join("secret"), b"secret").unwrap();
+
+        let opts = Options::parse(&args(&[
+            "--local-files", dir.to_str().unwrap(),
+        ])).unwrap();
+        let local = opts.local_files;
+        let path = |x: &str| String::from(dir.join(x).to_str().unwrap());
+
+        assert_eq!(read_local(&local, &path("photo.jpg")).unwrap(), b"photo");
+        assert!(read_local(&None, &path("photo.jpg")).is_err());
+        assert!(read_local(&local, &path("../secret")).is_err());
+        assert!(read_local(&local, &path("missing.jpg")).is_err());
+        assert!(read_local(&local, "/etc/passwd").is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn failure(body: &str, status: StatusCode) -> Failure {
+        let resp = HttpResponse { body: Some(body.as_bytes().to_vec()) };
+        match <GetFile as Request>::Response::deserialize(resp) {
+            Ok(_) => panic!("{} parsed", body),
+            Err(e) => classify(&e, status),
+        }
+    }
+
+    #[test]
+    fn classify_replies() {
+        assert!(matches!(
+            failure(r#"{"ok":false,"error_code":429,
+                        "description":"Too Many Requests: retry after 7",
+                        "parameters":{"retry_after":7}}"#,
+                    StatusCode::TooManyRequests),
+            Failure::RateLimit(7),
+        ));
+        assert!(matches!(
+            failure(r#"{"ok":false,"error_code":400,
+                        "description":"Bad Request: user not found"}"#,
+                    StatusCode::BadRequest),
+            Failure::NotFound(_),
+        ));
+        assert!(matches!(
+            failure(r#"{"ok":false,"error_code":502,
+                        "description":"Bad Gateway"}"#,
+                    StatusCode::BadGateway),
+            Failure::Transient(_),
+        ));
+        assert!(matches!(
+            failure(r#"{"ok":false,"error_code":400,
+                        "description":"Bad Request: wrong file_id"}"#,
+                    StatusCode::BadRequest),
+            Failure::Other(_),
+        ));
+        assert!(matches!(
+            failure("<html>Bad Gateway</html>", StatusCode::BadGateway),
+            Failure::Transient(_),
+        ));
+        assert!(matches!(
+            failure("<html>Forbidden</html>", StatusCode::Forbidden),
+            Failure::Other(_),
+        ));
+    }
+
+    /// Bot API reply of the stub to `method` with `args`, the `n`th time
+    /// it is asked. User 11 has photo `p11` but is rate limited at first,
+    /// user 12 has no photos and user 13 is gone.
+    fn stub_reply(method: &str, args: &Value, n: usize) -> (u16, String) {
+        let photo = r#"{"ok":true,"result":{"total_count":1,"photos":[[
+            {"file_id":"p11","width":160,"height":160}
+        ]]}}"#;
+        let user = args["user_id"].as_i64();
+        match (method, user, args["file_id"].as_str()) {
+            ("getUserProfilePhotos", Some(11), _) if n == 1 => (
+                429,
+                String::from(r#"{"ok":false,"error_code":429,
+                    "description":"Too Many Requests: retry after 1",
+                    "parameters":{"retry_after":1}}"#),
+            ),
+            ("getUserProfilePhotos", Some(11), _) => (200, String::from(photo)),
+            ("getUserProfilePhotos", Some(12), _) => (200, String::from(
+                r#"{"ok":true,"result":{"total_count":0,"photos":[]}}"#,
+            )),
+            ("getFile", _, Some("p11")) => (200, String::from(
+                r#"{"ok":true,"result":{"file_id":"p11",
+                    "file_path":"photos/p11.jpg"}}"#,
+            )),
+            _ => (400, String::from(
+                r#"{"ok":false,"error_code":400,
+                    "description":"Bad Request: user not found"}"#,
+            )),
+        }
+    }
+
+    /// Serves `stub_reply` and the file `photos/p11.jpg` on a free port.
+    /// Returns the API URL and the requests made, e.g. `getFile p11`.
+    fn stub() -> (String, Arc<Mutex<Vec<String>>>) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let log2 = log.clone();
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+            .serve(move || {
+                let log = log2.clone();
+                service_fn(move |req: hyper::Request<hyper::Body>| {
+                    let path = String::from(req.uri().path());
+                    let log = log.clone();
+                    req.into_body().concat2().map(move |body| {
+                        let args: Value = serde_json::from_slice(&body)
+                            .unwrap_or(Value::Null);
+                        let method = path.rsplit('/').next().unwrap();
+                        let key = match args["user_id"].as_i64() {
+                            Some(x) => format!("{} {}", method, x),
+                            None => format!("{} {}", method,
+                                            args["file_id"].as_str()
+                                                .unwrap_or_default()),
+                        };
+                        let mut log = log.lock().unwrap();
+                        log.push(key.clone());
+                        let n = log.iter().filter(|x| **x == key).count();
+                        let file = format!("/file/bot{}/photos/p11.jpg", TOKEN);
+                        let (status, text) = if path == file {
+                            (200, String::from("photo p11"))
+                        } else {
+                            stub_reply(method, &args, n)
+                        };
+                        hyper::Response::builder()
+                            .status(status)
+                            .body(hyper::Body::from(text))
+                            .unwrap()
+                    })
+                })
+            });
+        let url = format!("http://{}/", server.local_addr());
+        thread::spawn(move || {
+            hyper::rt::run(server.map_err(|e| panic!("{}", e)))
+        });
+        (url, log)
+    }
+
+    fn stub_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../scripts/init.sql")).unwrap();
+        conn.execute_batch("
+            INSERT INTO chats VALUES (1, 0, 1, 'c1', 'Chat', NULL);
+            INSERT INTO users VALUES (1, 0, 11, 'u1', 'Photo');
+            INSERT INTO users VALUES (2, 0, 12, 'u2', 'No photo');
+            INSERT INTO users VALUES (3, 0, 13, 'u3', 'Gone');
+            INSERT INTO messages
+            SELECT 1, id, CAST(strftime('%s', 'now') AS INTEGER)/3600 - id, 1
+              FROM users;
+            INSERT INTO users_tg VALUES (2, 0, 'old2');
+        ").unwrap();
+        conn
+    }
+
+    fn docs(conn: &Connection) -> Vec<(i64, Option<String>)> {
+        let mut docs = Vec::new();
+        db_util::query_map_named(
+            conn,
+            "SELECT id, doc FROM users_tg WHERE last_upd > 0 ORDER BY id",
+            &[],
+            |row| docs.push((row.get(0), row.get(1))),
+        ).unwrap();
+        docs
+    }
+
+    #[test]
+    fn run_syncs_from_stub() {
+        let (url, log) = stub();
+        let dir = env::temp_dir()
+            .join(format!("tg-ava-run-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        env::set_var("AVA_DIR", &dir);
+        fs::write(ava::path("u2"), b"old2").unwrap();
+
+        let mut conn = stub_db();
+        let opts = Options::parse(&args(&["--api-url", &url])).unwrap();
+        let mut s = Session::new(TOKEN, &opts);
+        let rows = db_get_rows(&mut conn, &opts).unwrap();
+        let start = Instant::now();
+        assert!(run(&mut conn, &mut s, rows, user_photo, db_set_have)
+            .unwrap());
+
+        // Flood control held the retry back
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        let log = log.lock().unwrap();
+        let count = |x: &str| log.iter().filter(|y| *y == x).count();
+        assert_eq!(count("getUserProfilePhotos 11"), 2);
+        assert_eq!(count("getFile p11"), 1);
+        assert_eq!(count("p11.jpg "), 1);
+        assert_eq!(s.bot.requests.get(), 6);
+
+        assert_eq!(docs(&conn), vec![
+            (1, Some(String::from("p11"))),
+            (2, None),
+            (3, None),
+        ]);
+        assert_eq!(fs::read(ava::path("u1")).unwrap(), b"photo p11");
+        assert!(fs::symlink_metadata(ava::path("u2")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn run_stops_at_budget() {
+        let (url, log) = stub();
+        let mut conn = stub_db();
+        conn.execute("DELETE FROM users_tg", &[]).unwrap();
+        let opts = Options::parse(&args(&[
+            "--api-url", &url,
+            "--budget", "1",
+            "--concurrency", "1",
+        ])).unwrap();
+        let mut s = Session::new(TOKEN, &opts);
+        let rows = db_get_rows(&mut conn, &opts)
+            .unwrap()
+            .into_iter()
+            .filter(|x| x.1 != 11)
+            .collect();
+        assert!(!run(&mut conn, &mut s, rows, user_photo, db_set_have)
+            .unwrap());
+
+        assert_eq!(*log.lock().unwrap(), vec!["getUserProfilePhotos 12"]);
+        assert_eq!(s.bot.requests.get(), 1);
+        assert_eq!(docs(&conn), vec![(2, None)]);
+    }
+}
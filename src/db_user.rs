@@ -0,0 +1,255 @@
+use rusqlite::Connection;
+use rusqlite::types::ToSql;
+use super::db::{self, Reply};
+use super::db_util;
+use super::error::MyError;
+use super::metrics;
+
+const TOP_PARTNERS: i64 = 10;
+
+#[derive(Debug, Serialize)]
+pub struct UserResult {
+    pub name: String,
+    pub hours: (i64, i64),
+
+    pub chat_ids: Vec<String>,
+    pub chat_names: Vec<String>,
+    pub messages_by_chat: Vec<i64>,
+
+    pub messages_by_hour: [i64; 24],
+    pub messages_by_weekday: [i64; 7],
+
+    // (first day, length in days)
+    pub longest_streak: (i64, i64),
+
+    pub replies_to_ids: Vec<String>,
+    pub replies_to_names: Vec<String>,
+    pub replies_to: Vec<i64>,
+
+    pub replies_from_ids: Vec<String>,
+    pub replies_from_names: Vec<String>,
+    pub replies_from: Vec<i64>,
+}
+
+pub fn query_http(
+    conn: &Connection,
+    user_rid: &str,
+    offset: i64,
+) -> (u16, String) {
+    db_util::http(query(conn, user_rid, offset))
+}
+
+pub fn query(
+    conn: &Connection,
+    user_rid: &str,
+    offset: i64,
+) -> Result<(u16, String), MyError> {
+    Ok(db::reply_json(profile(conn, user_rid, offset)?))
+}
+
+pub fn profile(
+    conn: &Connection,
+    user_rid: &str,
+    offset: i64,
+) -> Result<Reply<UserResult>, MyError> {
+    if !(-12..=12).contains(&offset) {
+        return Ok(Err((400, db::ERR_INVALID_OFFSET)));
+    }
+
+    let user_id = match db::search_user(conn, user_rid) {
+        Some(x) => x,
+        None => return Ok(Err((404, db::ERR_USER_NOT_FOUND))),
+    };
+
+    let mut result = UserResult {
+        name: String::new(),
+        hours: (0, 0),
+
+        chat_ids: Vec::new(),
+        chat_names: Vec::new(),
+        messages_by_chat: Vec::new(),
+
+        messages_by_hour: [0; 24],
+        messages_by_weekday: [0; 7],
+
+        longest_streak: (0, 0),
+
+        replies_to_ids: Vec::new(),
+        replies_to_names: Vec::new(),
+        replies_to: Vec::new(),
+
+        replies_from_ids: Vec::new(),
+        replies_from_names: Vec::new(),
+        replies_from: Vec::new(),
+    };
+
+    let args: &[(&str, &dyn ToSql)] = &[
+        (":user_id", &user_id),
+        (":offset", &offset),
+    ];
+
+    result.name = conn.query_row(
+        "SELECT name FROM users WHERE id = ?",
+        &[&user_id],
+        |row| row.get(0),
+    )?;
+
+    result.hours = conn.query_row_named(
+        "
+            SELECT COALESCE(MIN(hour), 0), COALESCE(MAX(hour), 0)
+              FROM messages
+             WHERE user_id = :user_id
+        ",
+        &[(":user_id", &user_id)],
+        |row| (row.get::<_, i64>(0), row.get::<_, i64>(1)),
+    )?;
+
+    db_util::query_map_named(
+        conn,
+        "
+            SELECT chats.rnd_id
+                 , chats.name
+                 , SUM(messages.count)
+              FROM messages
+             INNER JOIN chats ON chats.id = messages.chat_id
+             WHERE messages.user_id = :user_id
+               AND messages.chat_id NOT IN (SELECT id FROM chats_unlisted)
+             GROUP BY messages.chat_id
+             ORDER BY SUM(messages.count) DESC
+        ",
+        &[(":user_id", &user_id)],
+        |row| {
+            result.chat_ids.push(row.get(0));
+            result.chat_names.push(row.get(1));
+            result.messages_by_chat.push(row.get(2));
+        },
+    )?;
+
+    db_util::query_map_named(
+        conn,
+        "
+            SELECT (hour + :offset) % 24
+                 , SUM(count)
+              FROM messages
+             WHERE user_id = :user_id
+             GROUP BY (hour + :offset) % 24
+        ",
+        args,
+        |row| {
+            let hour: i64 = row.get(0);
+            result.messages_by_hour[hour as usize] = row.get(1);
+        },
+    )?;
+
+    db_util::query_map_named(
+        conn,
+        "
+            SELECT ((hour + :offset)/24 + 3)%7, SUM(count)
+              FROM messages
+             WHERE user_id = :user_id
+             GROUP BY ((hour + :offset)/24 + 3)%7
+        ",
+        args,
+        |row| {
+            let weekday: i64 = row.get(0);
+            result.messages_by_weekday[weekday as usize] = row.get(1);
+        },
+    )?;
+
+    let mut days = Vec::new();
+    db_util::query_map_named(
+        conn,
+        "
+            SELECT DISTINCT (hour + :offset)/24
+              FROM messages
+             WHERE user_id = :user_id
+             ORDER BY 1
+        ",
+        args,
+        |row| days.push(row.get(0)),
+    )?;
+    result.longest_streak = metrics::longest_streak(&days);
+
+    db_util::query_map_named(
+        conn,
+        "
+            SELECT users.rnd_id
+                 , users.name
+                 , SUM(replies.count)
+              FROM replies
+             INNER JOIN users ON users.id = replies.to_uid
+             WHERE replies.from_uid = :user_id
+               AND replies.to_uid != :user_id
+               AND replies.chat_id NOT IN (SELECT id FROM chats_unlisted)
+             GROUP BY replies.to_uid
+             ORDER BY SUM(replies.count) DESC
+             LIMIT :limit
+        ",
+        &[(":user_id", &user_id), (":limit", &TOP_PARTNERS)],
+        |row| {
+            result.replies_to_ids.push(row.get(0));
+            result.replies_to_names.push(row.get(1));
+            result.replies_to.push(row.get(2));
+        },
+    )?;
+
+    db_util::query_map_named(
+        conn,
+        "
+            SELECT users.rnd_id
+                 , users.name
+                 , SUM(replies.count)
+              FROM replies
+             INNER JOIN users ON users.id = replies.from_uid
+             WHERE replies.to_uid = :user_id
+               AND replies.from_uid != :user_id
+               AND replies.chat_id NOT IN (SELECT id FROM chats_unlisted)
+             GROUP BY replies.from_uid
+             ORDER BY SUM(replies.count) DESC
+             LIMIT :limit
+        ",
+        &[(":user_id", &user_id), (":limit", &TOP_PARTNERS)],
+        |row| {
+            result.replies_from_ids.push(row.get(0));
+            result.replies_from_names.push(row.get(1));
+            result.replies_from.push(row.get(2));
+        },
+    )?;
+
+    Ok(Ok(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../scripts/init.sql")).unwrap();
+        conn.execute_batch("
+            INSERT INTO users VALUES (1, 0, 1, 'u1', 'Alice');
+            INSERT INTO users VALUES (2, 0, 2, 'u2', 'Bob');
+            INSERT INTO users VALUES (3, 0, 3, 'u3', 'Carol');
+            INSERT INTO chats VALUES (1, 0, 1, 'c1', 'Public', NULL);
+            INSERT INTO chats VALUES (2, 0, 2, 'c2', 'Hidden', NULL);
+            INSERT INTO chats_unlisted VALUES (2);
+            INSERT INTO messages VALUES (1, 1, 420000, 3);
+            INSERT INTO messages VALUES (2, 1, 420001, 5);
+            INSERT INTO replies VALUES (1, 1, 2, 4);
+            INSERT INTO replies VALUES (2, 1, 3, 7);
+            INSERT INTO replies VALUES (2, 3, 1, 2);
+        ").unwrap();
+        conn
+    }
+
+    #[test]
+    fn profile_hides_unlisted_chats() {
+        let conn = test_db();
+        let r = profile(&conn, "u1", 0).unwrap().unwrap();
+        assert_eq!(r.chat_ids, vec!["c1"]);
+        assert_eq!(r.messages_by_chat, vec![3]);
+        assert_eq!(r.replies_to_ids, vec!["u2"]);
+        assert_eq!(r.replies_to, vec![4]);
+        assert!(r.replies_from_ids.is_empty());
+    }
+}
@@ -6,11 +6,11 @@ use super::error::MyError;
 pub fn query_map_named<F>(
     conn: &Connection,
     sql: &str,
-    params: &[(&str, &ToSql)],
+    params: &[(&str, &dyn ToSql)],
     f: F,
 ) -> Result<(), MyError>
 where
-    F: FnMut(&Row) -> (),
+    F: FnMut(&Row),
 {
     let mut ff = f; // XXX: WTF?
     let mut stmt = conn.prepare(sql)?;
@@ -22,7 +22,7 @@ where
 pub fn query_row<T, F>(
     conn: &Connection,
     sql: &str,
-    params: &[&ToSql],
+    params: &[&dyn ToSql],
     f: F,
 ) -> Result<Option<T>, Error>
 where
@@ -35,15 +35,38 @@ where
     }
 }
 
+pub fn query_row_named<T, F>(
+    conn: &Connection,
+    sql: &str,
+    params: &[(&str, &dyn ToSql)],
+    f: F,
+) -> Result<Option<T>, Error>
+where
+    F: FnOnce(&Row) -> T,
+{
+    match conn.query_row_named(sql, params, f) {
+        Ok(x) => Ok(Some(x)),
+        Err(Error::QueryReturnedNoRows) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn http(res: Result<(u16, String), MyError>) -> (u16, String) {
+    match res {
+        Ok(res) => res,
+        Err(e) => (500, format!("Error:\n{:?}", e)),
+    }
+}
+
 pub fn random_id() -> String {
     let mut result = String::from("");
     let mut rng = thread_rng();
     for _ in 0..8 {
         let v = (rng.gen::<u32>() % (10+26+26)) as u8;
         let v = v + match v {
-            00..=09 => '0' as u8,
-            10..=35 => 'a' as u8 - 10,
-            36..=61 => 'A' as u8 - 36,
+            0..=9 => b'0',
+            10..=35 => b'a' - 10,
+            36..=61 => b'A' - 36,
             _ => 0,
         };
         result.push(v as char);
@@ -0,0 +1,385 @@
+use rusqlite::Connection;
+use rusqlite::types::ToSql;
+use super::date::{self, Bucket};
+use super::db::{self, Filter, Params, QueryResult, Reply};
+use super::db_util;
+use super::error::MyError;
+use super::metrics;
+
+const TOP_USERS: usize = 10;
+const TOP_PARTNERS: i64 = 10;
+
+#[derive(Debug, Serialize)]
+pub struct WrappedResult {
+    title: String,
+    year: i64,
+    user_name: Option<String>,
+
+    messages: i64,
+    // Active users of the chat, even for a user
+    users: i64,
+    // Among listed chats, or among the chat's posters for a user. Absent
+    // without messages in the year.
+    rank: Option<i64>,
+
+    most_active_month: Option<String>,
+    most_active_weekday: Option<usize>,
+    most_active_hour: Option<usize>,
+    messages_by_month: Vec<i64>,
+
+    // (first day, length in days)
+    longest_streak: Option<(String, i64)>,
+
+    // Relative change since the previous year, absent if it had no data
+    previous_messages: i64,
+    previous_users: i64,
+    messages_growth: Option<f64>,
+    users_growth: Option<f64>,
+
+    top_user_ids: Vec<String>,
+    top_user_names: Vec<String>,
+    top_user_messages: Vec<i64>,
+
+    // All-time: replies are not stored per date. For a chat these are the
+    // most frequent (from, to) pairs, for a user their partners both ways.
+    reply_from_ids: Vec<String>,
+    reply_from_names: Vec<String>,
+    reply_to_ids: Vec<String>,
+    reply_to_names: Vec<String>,
+    reply_counts: Vec<i64>,
+}
+
+pub fn query_http(
+    conn: &Connection,
+    chat: &str,
+    user: Option<&str>,
+    year: Option<i64>,
+    offset: i64,
+) -> (u16, String) {
+    db_util::http(query(conn, chat, user, year, offset))
+}
+
+pub fn query(
+    conn: &Connection,
+    chat: &str,
+    user: Option<&str>,
+    year: Option<i64>,
+    offset: i64,
+) -> Result<(u16, String), MyError> {
+    Ok(db::reply_json(wrapped(conn, chat, user, year, offset)?))
+}
+
+fn year_params<'a>(
+    year: i64,
+    offset: i64,
+    users: &'a [String],
+) -> Params<'a> {
+    Params {
+        dates: Some((
+            date::days_from_civil(year, 1, 1),
+            date::days_from_civil(year, 12, 31),
+        )),
+        offset,
+        users,
+        exclude: &[],
+        weekday: None,
+        hours: None,
+        bucket: Bucket::Month,
+    }
+}
+
+fn total(stats: &QueryResult) -> (i64, i64) {
+    (stats.daily_messages.iter().sum(), stats.messages_by_user.len() as i64)
+}
+
+fn growth(now: i64, before: i64) -> Option<f64> {
+    if before == 0 {
+        None
+    } else {
+        Some((now - before) as f64 / before as f64)
+    }
+}
+
+fn argmax(xs: &[i64]) -> Option<usize> {
+    let max = *xs.iter().max()?;
+    if max == 0 {
+        return None;
+    }
+    xs.iter().position(|&x| x == max)
+}
+
+fn wrapped(
+    conn: &Connection,
+    chat: &str,
+    user: Option<&str>,
+    year: Option<i64>,
+    offset: i64,
+) -> Result<Reply<WrappedResult>, MyError> {
+    let year = year.unwrap_or_else(|| {
+        date::civil_from_days(date::today(offset)).0
+    });
+    if !(date::MIN_YEAR..=date::MAX_YEAR).contains(&year) {
+        return Ok(Err((400, db::ERR_INVALID_DATES)));
+    }
+
+    let users: Vec<String> = user.iter().map(|x| x.to_string()).collect();
+    let p = year_params(year, offset, &users);
+    let all = year_params(year, offset, &[]);
+
+    macro_rules! try_reply {
+        ($e:expr) => {
+            match $e? {
+                Ok(x) => x,
+                Err(e) => return Ok(Err(e)),
+            }
+        };
+    }
+
+    let chat_stats = try_reply!(db::stats(conn, chat, &all));
+    let user_stats = match user {
+        Some(_) => Some(try_reply!(db::stats(conn, chat, &p))),
+        None => None,
+    };
+    let stats = user_stats.as_ref().unwrap_or(&chat_stats);
+
+    // The previous year may be before the earliest allowed date.
+    let prev = year_params(year - 1, offset, &users);
+    let prev_all = year_params(year - 1, offset, &[]);
+    let (previous_messages, previous_users) = match prev.check() {
+        Ok(()) => (
+            total(&try_reply!(db::stats(conn, chat, &prev))).0,
+            total(&try_reply!(db::stats(conn, chat, &prev_all))).1,
+        ),
+        Err(_) => (0, 0),
+    };
+
+    let (chat_id, _) = match db::search_chat(conn, chat) {
+        Some(x) => x,
+        None => return Ok(Err((404, db::ERR_CHAT_NOT_FOUND))),
+    };
+
+    let messages = total(stats).0;
+    let active_users = total(&chat_stats).1;
+    let mut result = WrappedResult {
+        title: stats.title.clone(),
+        year,
+        user_name: user.and(stats.user_names.first().cloned()),
+
+        messages,
+        users: active_users,
+        rank: None,
+
+        most_active_month: argmax(&stats.daily_messages)
+            .map(|i| stats.bucket_starts[i][..7].to_string()),
+        most_active_weekday: argmax(&stats.messages_by_weekday),
+        most_active_hour: argmax(&stats.messages_by_hour),
+        messages_by_month: stats.daily_messages.clone(),
+
+        longest_streak: None,
+
+        previous_messages,
+        previous_users,
+        messages_growth: growth(messages, previous_messages),
+        users_growth: growth(active_users, previous_users),
+
+        top_user_ids: Vec::new(),
+        top_user_names: Vec::new(),
+        top_user_messages: Vec::new(),
+
+        reply_from_ids: Vec::new(),
+        reply_from_names: Vec::new(),
+        reply_to_ids: Vec::new(),
+        reply_to_names: Vec::new(),
+        reply_counts: Vec::new(),
+    };
+
+    for i in 0..chat_stats.user_ids.len().min(TOP_USERS) {
+        result.top_user_ids.push(chat_stats.user_ids[i].clone());
+        result.top_user_names.push(chat_stats.user_names[i].clone());
+        result.top_user_messages.push(chat_stats.messages_by_user[i]);
+    }
+
+    let filter = try_reply!(Filter::new(conn, &p));
+    let mut args: Vec<(&str, &dyn ToSql)> = Vec::new();
+    args.push((":chat_id", &chat_id));
+    args.push((":offset", &offset));
+    filter.args(&mut args);
+
+    let mut days = Vec::new();
+    db_util::query_map_named(
+        conn,
+        format!("
+            SELECT DISTINCT (hour + :offset)/24
+              FROM messages
+             WHERE chat_id = :chat_id
+                   {}
+             ORDER BY 1
+        ", filter.sql).as_ref(),
+        args.as_slice(),
+        |row| days.push(row.get(0)),
+    )?;
+    if !days.is_empty() {
+        let (start, length) = metrics::longest_streak(&days);
+        result.longest_streak = Some((date::format_day(start), length));
+    }
+
+    match user {
+        Some(user) => {
+            result.rank = chat_stats
+                .user_ids
+                .iter()
+                .position(|x| x == user)
+                .map(|x| x as i64 + 1);
+            user_replies(conn, chat_id, user, &mut result)?;
+        }
+        None => {
+            if messages > 0 {
+                result.rank = Some(chat_rank(conn, chat_id, &all, messages)?);
+            }
+            chat_replies(conn, chat_id, &mut result)?;
+        }
+    }
+
+    Ok(Ok(result))
+}
+
+/// Position of the chat among listed chats by messages in the range.
+fn chat_rank(
+    conn: &Connection,
+    chat_id: i64,
+    p: &Params,
+    messages: i64,
+) -> Result<i64, MyError> {
+    let (from, to) = p.dates.unwrap();
+    let hour_from = from*24 - p.offset;
+    let hour_to = to*24 - p.offset + 23;
+    Ok(conn.query_row_named(
+        "
+            SELECT COUNT(*) + 1
+              FROM (
+                    SELECT chat_id, SUM(count) AS messages
+                      FROM messages
+                     WHERE hour BETWEEN :hour_from AND :hour_to
+                     GROUP BY chat_id
+                   )
+             WHERE messages > :messages
+               AND chat_id != :chat_id
+               AND chat_id NOT IN (SELECT id FROM chats_unlisted)
+        ",
+        &[
+            (":hour_from", &hour_from),
+            (":hour_to", &hour_to),
+            (":messages", &messages),
+            (":chat_id", &chat_id),
+        ],
+        |row| row.get(0),
+    )?)
+}
+
+fn chat_replies(
+    conn: &Connection,
+    chat_id: i64,
+    result: &mut WrappedResult,
+) -> Result<(), MyError> {
+    db_util::query_map_named(
+        conn,
+        "
+            SELECT a.rnd_id, a.name, b.rnd_id, b.name, replies.count
+              FROM replies
+             INNER JOIN users AS a ON a.id = replies.from_uid
+             INNER JOIN users AS b ON b.id = replies.to_uid
+             WHERE replies.chat_id = :chat_id
+               AND replies.from_uid != replies.to_uid
+             ORDER BY replies.count DESC
+             LIMIT :limit
+        ",
+        &[(":chat_id", &chat_id), (":limit", &TOP_PARTNERS)],
+        |row| {
+            result.reply_from_ids.push(row.get(0));
+            result.reply_from_names.push(row.get(1));
+            result.reply_to_ids.push(row.get(2));
+            result.reply_to_names.push(row.get(3));
+            result.reply_counts.push(row.get(4));
+        },
+    )
+}
+
+fn user_replies(
+    conn: &Connection,
+    chat_id: i64,
+    user_rid: &str,
+    result: &mut WrappedResult,
+) -> Result<(), MyError> {
+    let user_id = match db::search_user(conn, user_rid) {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let name = result.user_name.clone().unwrap_or_default();
+    db_util::query_map_named(
+        conn,
+        "
+            SELECT users.rnd_id, users.name, SUM(count)
+              FROM (
+                    SELECT to_uid AS partner, count
+                      FROM replies
+                     WHERE chat_id = :chat_id AND from_uid = :user_id
+                     UNION ALL
+                    SELECT from_uid AS partner, count
+                      FROM replies
+                     WHERE chat_id = :chat_id AND to_uid = :user_id
+                   )
+             INNER JOIN users ON users.id = partner
+             WHERE partner != :user_id
+             GROUP BY partner
+             ORDER BY 3 DESC
+             LIMIT :limit
+        ",
+        &[
+            (":chat_id", &chat_id),
+            (":user_id", &user_id),
+            (":limit", &TOP_PARTNERS),
+        ],
+        |row| {
+            result.reply_from_ids.push(user_rid.to_string());
+            result.reply_from_names.push(name.clone());
+            result.reply_to_ids.push(row.get(0));
+            result.reply_to_names.push(row.get(1));
+            result.reply_counts.push(row.get(2));
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../scripts/init.sql")).unwrap();
+        conn.execute_batch("
+            INSERT INTO chats VALUES (1, 0, 1, 'c1', 'Quiet', NULL);
+            INSERT INTO chats VALUES (2, 0, 2, 'c2', 'Busy', NULL);
+            INSERT INTO users VALUES (1, 0, 1, 'u1', 'Alice');
+            INSERT INTO users VALUES (2, 0, 2, 'u2', 'Bob');
+            INSERT INTO messages VALUES (1, 1, 17532*24, 3);
+            INSERT INTO messages VALUES (2, 1, 17532*24, 5);
+            INSERT INTO messages VALUES (2, 2, 17533*24, 6);
+        ").unwrap();
+        conn
+    }
+
+    #[test]
+    fn rank_in_year() {
+        let conn = test_db();
+        let rank = |chat, user, year| {
+            wrapped(&conn, chat, user, Some(year), 0).unwrap().unwrap().rank
+        };
+        assert_eq!(rank("c1", None, 2018), Some(2));
+        assert_eq!(rank("c2", None, 2018), Some(1));
+        assert_eq!(rank("c2", Some("u2"), 2018), Some(1));
+        assert_eq!(rank("c2", Some("u1"), 2018), Some(2));
+        assert_eq!(rank("c1", Some("u2"), 2018), None);
+        assert_eq!(rank("c1", None, 2017), None);
+        assert_eq!(rank("c1", Some("u1"), 2017), None);
+    }
+}
@@ -6,7 +6,10 @@ use std::io;
 use reqwest;
 use telegram_bot;
 
+// The payloads are only read through Debug, and the variants are named
+// after the wrapped errors
 #[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
 pub enum MyError {
     SqlError(rusqlite::Error),
     IoError(io::Error),
@@ -17,7 +20,7 @@ impl error::Error for MyError {
     fn description(&self) -> &str {
         "Shit happens"
     }
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         None
     }
 }
@@ -0,0 +1,357 @@
+use rusqlite::Connection;
+use std::fs;
+use std::ops::Deref;
+use std::path::Path;
+use super::date::{self, Bucket};
+use super::db::{self, Filter, Params, QueryResult, Reply};
+use super::error::MyError;
+use super::serde_json;
+
+pub const ERR_INVALID_TABLE: &str = r#"{"error":"invalid table"}"#;
+
+/// Tables available as separate CSV documents.
+pub const TABLES: [&str; 4] = ["daily", "hours", "weekdays", "users"];
+
+const WEEKDAYS: [&str; 7] = [
+    "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday",
+    "Sunday",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Ndjson,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "table", rename_all = "lowercase")]
+enum Line<'a> {
+    Daily {
+        date: &'a str,
+        users: i64,
+        messages: i64,
+        weekly_active_users: Option<i64>,
+        monthly_active_users: Option<i64>,
+    },
+    Hours { hour: usize, messages: i64 },
+    Weekdays { weekday: &'static str, messages: i64 },
+    Users { user_id: &'a str, name: &'a str, messages: i64 },
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn lines<'a>(r: &'a QueryResult, table: &str) -> Vec<Line<'a>> {
+    match table {
+        "daily" => r.bucket_starts.iter().enumerate().map(|(i, date)| {
+            Line::Daily {
+                date,
+                users: r.daily_users[i],
+                messages: r.daily_messages[i],
+                weekly_active_users: r.weekly_active_users.get(i).cloned(),
+                monthly_active_users: r.monthly_active_users.get(i).cloned(),
+            }
+        }).collect(),
+        "hours" => r.messages_by_hour.iter().enumerate().map(|(i, &n)| {
+            Line::Hours { hour: i, messages: n }
+        }).collect(),
+        "weekdays" => r.messages_by_weekday.iter().enumerate().map(|(i, &n)| {
+            Line::Weekdays { weekday: WEEKDAYS[i], messages: n }
+        }).collect(),
+        "users" => (0..r.user_ids.len()).map(|i| {
+            Line::Users {
+                user_id: &r.user_ids[i],
+                name: &r.user_names[i],
+                messages: r.messages_by_user[i],
+            }
+        }).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Renders one of `TABLES` as a CSV document with a header row.
+pub fn csv(r: &QueryResult, table: &str) -> String {
+    let mut out = String::from(match table {
+        "daily" => "date,users,messages,weekly_active_users,\
+                    monthly_active_users\n",
+        "hours" => "hour,messages\n",
+        "weekdays" => "weekday,messages\n",
+        "users" => "user_id,name,messages\n",
+        _ => "",
+    });
+    let opt = |x: Option<i64>| x.map(|x| x.to_string()).unwrap_or_default();
+    for line in lines(r, table) {
+        out += &match line {
+            Line::Daily {
+                date, users, messages, weekly_active_users,
+                monthly_active_users,
+            } => format!(
+                "{},{},{},{},{}\n",
+                date,
+                users,
+                messages,
+                opt(weekly_active_users),
+                opt(monthly_active_users),
+            ),
+            Line::Hours { hour, messages } =>
+                format!("{},{}\n", hour, messages),
+            Line::Weekdays { weekday, messages } =>
+                format!("{},{}\n", weekday, messages),
+            Line::Users { user_id, name, messages } =>
+                format!("{},{},{}\n", user_id, csv_field(name), messages),
+        };
+    }
+    out
+}
+
+/// Renders all tables as newline-delimited JSON. Each object carries a
+/// `table` field naming the table it belongs to.
+pub fn ndjson(r: &QueryResult) -> String {
+    ndjson_tables(r, &TABLES)
+}
+
+fn ndjson_tables(r: &QueryResult, tables: &[&str]) -> String {
+    tables.iter()
+        .flat_map(|table| lines(r, table))
+        .map(|line| serde_json::to_string(&line).unwrap() + "\n")
+        .collect()
+}
+
+/// Splits `(from, to)` into ranges `Params::check` accepts for `bucket`,
+/// each but the last ending right before a bucket starts.
+fn windows(bucket: Bucket, (from, to): (i64, i64)) -> Vec<(i64, i64)> {
+    let mut result = Vec::new();
+    let mut start = from;
+    while start <= to {
+        let next = bucket.start(start + bucket.max_days()).max(start + 1);
+        let end = (next - 1).min(to);
+        result.push((start, end));
+        start = end + 1;
+    }
+    result
+}
+
+/// Checks `p` for `ndjson_stream` and resolves the range to stream. Its
+/// length isn't capped, but it is narrowed to the days the chat has
+/// messages on, all of them if no range is given.
+pub fn ndjson_range(
+    conn: &Connection,
+    chat: &str,
+    p: &Params,
+) -> Result<Reply<(i64, i64)>, MyError> {
+    let mut unbounded = *p;
+    unbounded.dates = None;
+    if let Err(e) = unbounded.check() {
+        return Ok(Err(e));
+    }
+    if let Err(e) = Filter::new(conn, &unbounded)? {
+        return Ok(Err(e));
+    }
+    let chat_id = match db::search_chat(conn, chat) {
+        Some(x) => x.0,
+        None => return Ok(Err((404, db::ERR_CHAT_NOT_FOUND))),
+    };
+
+    let today = date::today(p.offset);
+    let (first, last) = conn.query_row_named(
+        "
+            SELECT (MIN(hour) + :offset)/24, (MAX(hour) + :offset)/24
+              FROM messages
+             WHERE chat_id = :chat_id
+        ",
+        &[(":chat_id", &chat_id), (":offset", &p.offset)],
+        |row| (
+            row.get::<_, Option<i64>>(0).unwrap_or(today),
+            row.get::<_, Option<i64>>(1).unwrap_or(today),
+        ),
+    )?;
+    Ok(Ok(match p.dates {
+        None => (first, last),
+        Some((from, to)) if from <= to => {
+            // A single day at the edge if no messages are in range
+            let from = from.max(first).min(to);
+            (from, to.min(last).max(from))
+        }
+        Some(_) => return Ok(Err((400, db::ERR_INVALID_DATES))),
+    }))
+}
+
+/// Renders NDJSON for `dates` from `ndjson_range`, handing it to `send` a
+/// piece at a time: the daily table in ranges `Params::check` accepts,
+/// then the other tables over the whole range. `conn` is only held for one
+/// piece, so that other queries run in between. Stops early once `send`
+/// returns false.
+pub fn ndjson_stream<C, G, S>(
+    conn: C,
+    chat: &str,
+    p: &Params,
+    dates: (i64, i64),
+    mut send: S,
+) -> Result<Reply<()>, MyError>
+where
+    C: Fn() -> G,
+    G: Deref<Target = Connection>,
+    S: FnMut(String) -> bool,
+{
+    for window in windows(p.bucket, dates) {
+        let mut q = *p;
+        q.dates = Some(window);
+        let r = match db::stats_rolling(&conn(), chat, &q)? {
+            Ok(x) => x,
+            Err(e) => return Ok(Err(e)),
+        };
+        if !send(ndjson_tables(&r, &TABLES[..1])) {
+            return Ok(Ok(()));
+        }
+    }
+
+    let mut q = *p;
+    q.dates = Some(dates);
+    q.bucket = Bucket::Year;
+    let r = match db::stats(&conn(), chat, &q)? {
+        Ok(x) => x,
+        Err(e) => return Ok(Err(e)),
+    };
+    send(ndjson_tables(&r, &TABLES[1..]));
+    Ok(Ok(()))
+}
+
+/// Runs the stats query and renders it in `format`. `table` selects the
+/// CSV document (`daily` if absent).
+///
+/// The document is built in memory from the aggregated stats, so its size
+/// is bounded by the range `Params::check` allows for the bucket, at most
+/// `Bucket::max_days` buckets. Longer NDJSON goes through `ndjson_stream`.
+pub fn query(
+    conn: &Connection,
+    chat: &str,
+    p: &Params,
+    format: Format,
+    table: Option<&str>,
+) -> Result<Reply<String>, MyError> {
+    let table = table.unwrap_or("daily");
+    if !TABLES.contains(&table) {
+        return Ok(Err((400, ERR_INVALID_TABLE)));
+    }
+
+    let r = match db::stats_rolling(conn, chat, p)? {
+        Ok(x) => x,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    Ok(Ok(match format {
+        Format::Csv => csv(&r, table),
+        Format::Ndjson => ndjson(&r),
+    }))
+}
+
+/// Writes every CSV table and `stats.ndjson` into `dir`.
+pub fn write_files(
+    conn: &Connection,
+    chat: &str,
+    p: &Params,
+    dir: &str,
+) -> Result<Reply<()>, MyError> {
+    let r = match db::stats_rolling(conn, chat, p)? {
+        Ok(x) => x,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir)?;
+    for table in TABLES.iter() {
+        fs::write(dir.join(format!("{}.csv", table)), csv(&r, table))?;
+    }
+    fs::write(dir.join("stats.ndjson"), ndjson(&r))?;
+
+    Ok(Ok(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../scripts/init.sql")).unwrap();
+        conn.execute_batch("
+            INSERT INTO chats VALUES (1, 0, 1, 'c1', 'Chat', NULL);
+            INSERT INTO users VALUES (1, 0, 1, 'u1', 'Alice');
+            INSERT INTO users VALUES (2, 0, 2, 'u2', 'Bob');
+            WITH RECURSIVE days(day) AS (
+                SELECT 17000 UNION ALL SELECT day + 3 FROM days LIMIT 1000
+            )
+            INSERT INTO messages
+            SELECT 1, 1 + day % 2, day * 24 + day % 24, 1 + day % 5
+              FROM days;
+        ").unwrap();
+        conn
+    }
+
+    fn stream(conn: &Connection, p: &Params) -> Result<String, u16> {
+        let dates = ndjson_range(conn, "c1", p).unwrap().map_err(|e| e.0)?;
+        let mut out = String::new();
+        ndjson_stream(|| conn, "c1", p, dates, |x| {
+            out += &x;
+            true
+        }).unwrap().unwrap();
+        Ok(out)
+    }
+
+    #[test]
+    fn windows_split_on_buckets() {
+        assert_eq!(windows(Bucket::Day, (10, 10)), vec![(10, 10)]);
+        assert_eq!(
+            windows(Bucket::Day, (0, 2500)),
+            vec![(0, 999), (1000, 1999), (2000, 2500)],
+        );
+        for &(from, to) in windows(Bucket::Week, (3, 20000)).iter() {
+            assert!(to - from < Bucket::Week.max_days());
+            if to != 20000 {
+                assert_eq!(Bucket::Week.start(to + 1), to + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn ndjson_stream_matches_one_query() {
+        let conn = test_db();
+        let p = Params::range(Some((17100, 17900)), 0);
+        let r = db::stats_rolling(&conn, "c1", &p).unwrap().unwrap();
+        assert_eq!(stream(&conn, &p), Ok(ndjson(&r)));
+    }
+
+    #[test]
+    fn ndjson_stream_long_range() {
+        let conn = test_db();
+        let p = Params::range(None, 0);
+        let out = stream(&conn, &p).unwrap();
+        let count = |table: &str| {
+            let tag = format!("{{\"table\":\"{}\"", table);
+            out.lines().filter(|x| x.starts_with(&tag)).count()
+        };
+        assert_eq!(count("daily"), 2998);
+        assert_eq!(count("hours"), 24);
+        assert_eq!(count("users"), 2);
+
+        let p = Params::range(Some((0, 100_000)), 0);
+        assert_eq!(stream(&conn, &p).unwrap(), out);
+
+        let p = Params::range(Some((10, 5)), 0);
+        assert_eq!(stream(&conn, &p), Err(400));
+    }
+}
@@ -0,0 +1,319 @@
+use rusqlite::Connection;
+use url::form_urlencoded;
+use super::chart::{self, escape};
+use super::date::{self, Bucket};
+use super::db::{self, Params, Reply};
+use super::db_chats;
+use super::db_user;
+use super::error::MyError;
+use super::serde_json;
+
+pub const CONTENT_TYPE: &str = "text/html; charset=utf-8";
+
+/// Chats per directory page, unless given.
+pub const PAGE_SIZE: i64 = 50;
+
+const LAYOUT: &str = include_str!("../templates/layout.html");
+const DIRECTORY: &str = include_str!("../templates/directory.html");
+const DASHBOARD: &str = include_str!("../templates/dashboard.html");
+const PROFILE: &str = include_str!("../templates/profile.html");
+const ERROR: &str = include_str!("../templates/error.html");
+
+const WEEKDAYS: [&str; 7] = [
+    "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday",
+    "Sunday",
+];
+
+/// Replaces each `{{name}}` in `template` with its value. Values are
+/// inserted as is, so text must be escaped by the caller.
+fn fill(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out += &rest[..start];
+        let end = match rest[start..].find("}}") {
+            Some(x) => start + x,
+            None => break,
+        };
+        let name = &rest[start + 2..end];
+        match vars.iter().find(|x| x.0 == name) {
+            Some(&(_, value)) => out += value,
+            None => out += &rest[start..end + 2],
+        }
+        rest = &rest[end + 2..];
+    }
+    out + rest
+}
+
+fn page(title: &str, body: &str) -> String {
+    fill(LAYOUT, &[("title", &escape(title)), ("body", body)])
+}
+
+fn error_page(status: u16, message: &str) -> String {
+    let body = fill(ERROR, &[
+        ("status", &status.to_string()),
+        ("message", &escape(message)),
+    ]);
+    page(&status.to_string(), &body)
+}
+
+/// Turns a rendered page or one of the JSON `ERR_*` bodies into an HTTP
+/// reply with an HTML error page.
+fn http(res: Result<Reply<String>, MyError>) -> (u16, String) {
+    match res {
+        Ok(Ok(html)) => (200, html),
+        Ok(Err((status, err))) => {
+            let message = serde_json::from_str::<serde_json::Value>(err)
+                .ok()
+                .and_then(|x| x["error"].as_str().map(String::from))
+                .unwrap_or_else(|| String::from(err));
+            (status, error_page(status, &message))
+        }
+        Err(e) => (500, error_page(500, &format!("Error:\n{:?}", e))),
+    }
+}
+
+fn link(href: &str, text: &str) -> String {
+    format!(r#"<a href="{}">{}</a>"#, escape(href), escape(text))
+}
+
+fn avatar(url: &Option<String>, size: u32) -> String {
+    match *url {
+        Some(ref url) => format!(
+            concat!(
+                r#"<img class="ava" src="{}?size={s}" width="{s}""#,
+                r#" height="{s}" alt=""> "#,
+            ),
+            escape(url),
+            s = size,
+        ),
+        None => String::new(),
+    }
+}
+
+fn percent(part: i64, total: i64) -> String {
+    if total == 0 {
+        String::from("0%")
+    } else {
+        format!("{:.1}%", part as f64 * 100.0 / total as f64)
+    }
+}
+
+pub fn directory_http(
+    conn: &Connection,
+    q: &str,
+    sort: Option<&str>,
+    page: i64,
+    limit: i64,
+) -> (u16, String) {
+    http(directory(conn, q, sort, page, limit))
+}
+
+/// Searchable list of listed chats, most active first.
+pub fn directory(
+    conn: &Connection,
+    q: &str,
+    sort: Option<&str>,
+    page_no: i64,
+    limit: i64,
+) -> Result<Reply<String>, MyError> {
+    let r = match db_chats::list(conn, q, sort, page_no, limit)? {
+        Ok(x) => x,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    let mut rows = String::new();
+    for i in 0..r.chat_ids.len() {
+        let alias = match r.chat_aliases[i] {
+            Some(ref alias) => {
+                format!(r#" <span class="muted">{}</span>"#, escape(alias))
+            }
+            None => String::new(),
+        };
+        let last = r.last_hours[i]
+            .map(|x| date::format_day(x / 24))
+            .unwrap_or_default();
+        rows += &format!(
+            concat!(
+                "<tr><td>{}{}{}</td><td class=\"n\">{}</td>",
+                "<td class=\"n\">{}</td><td>{}</td></tr>\n",
+            ),
+            avatar(&r.chat_avatars[i], 32),
+            link(&format!("/c/{}", r.chat_ids[i]), &r.chat_names[i]),
+            alias,
+            r.messages[i],
+            r.active_users[i],
+            last,
+        );
+    }
+
+    let page_link = |n: i64, text: &str| {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        if !q.is_empty() {
+            query.append_pair("q", q);
+        }
+        if let Some(sort) = sort {
+            query.append_pair("sort", sort);
+        }
+        query.append_pair("page", &n.to_string());
+        if limit != PAGE_SIZE {
+            query.append_pair("limit", &limit.to_string());
+        }
+        link(&format!("/?{}", query.finish()), text)
+    };
+    let mut pages = String::new();
+    if page_no > 0 {
+        pages += &page_link(page_no - 1, "Previous");
+    }
+    // `list` made sure that `page_no * limit` doesn't overflow
+    if page_no * limit < r.total - limit {
+        pages += " ";
+        pages += &page_link(page_no + 1, "Next");
+    }
+
+    let body = fill(DIRECTORY, &[
+        ("q", &escape(q)),
+        ("total", &r.total.to_string()),
+        ("rows", &rows),
+        ("pages", &pages),
+    ]);
+    Ok(Ok(page("Chats", &body)))
+}
+
+pub fn dashboard_http(
+    conn: &Connection,
+    chat: &str,
+    p: &Params,
+    query: Option<&str>,
+) -> (u16, String) {
+    http(dashboard(conn, chat, p, query))
+}
+
+/// Charts and tables for a chat. `query` is the original query string,
+/// kept for the CSV link.
+pub fn dashboard(
+    conn: &Connection,
+    chat: &str,
+    p: &Params,
+    query: Option<&str>,
+) -> Result<Reply<String>, MyError> {
+    let r = match db::stats(conn, chat, p)? {
+        Ok(x) => x,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    let messages: i64 = r.messages_by_user.iter().sum();
+    let range = match (&r.from, &r.to) {
+        (Some(from), Some(to)) => format!("{} to {}", from, to),
+        _ => format!(
+            "{} to {}",
+            date::format_day(r.hours.0 / 24),
+            date::format_day(r.hours.1 / 24),
+        ),
+    };
+    let heatmap = if p.bucket == Bucket::Day {
+        chart::heatmap(&r)
+    } else {
+        String::new()
+    };
+
+    let mut weekdays = String::new();
+    for (i, name) in WEEKDAYS.iter().enumerate() {
+        weekdays += &format!(
+            "<tr><td>{}</td><td class=\"n\">{}</td></tr>\n",
+            name,
+            r.messages_by_weekday[i],
+        );
+    }
+
+    let mut users = String::new();
+    for i in 0..r.user_ids.len() {
+        users += &format!(
+            concat!(
+                "<tr><td>{}</td><td>{}</td><td class=\"n\">{}</td>",
+                "<td class=\"n\">{}</td></tr>\n",
+            ),
+            i + 1,
+            link(&format!("/u/{}", r.user_ids[i]), &r.user_names[i]),
+            r.messages_by_user[i],
+            percent(r.messages_by_user[i], messages),
+        );
+    }
+
+    let query = query.map(|x| format!("?{}", x)).unwrap_or_default();
+    let body = fill(DASHBOARD, &[
+        ("avatar", &avatar(&r.avatar, 64)),
+        ("title", &escape(&r.title)),
+        ("range", &range),
+        ("messages", &messages.to_string()),
+        ("users", &r.user_ids.len().to_string()),
+        ("chat", &escape(chat)),
+        ("query", &escape(&query)),
+        ("daily", &chart::daily(&r)),
+        ("heatmap", &heatmap),
+        ("hours", &chart::hours(&r)),
+        ("weekdays", &weekdays),
+        ("users_rows", &users),
+    ]);
+    Ok(Ok(page(&r.title, &body)))
+}
+
+pub fn profile_http(
+    conn: &Connection,
+    user_rid: &str,
+    offset: i64,
+) -> (u16, String) {
+    http(profile(conn, user_rid, offset))
+}
+
+pub fn profile(
+    conn: &Connection,
+    user_rid: &str,
+    offset: i64,
+) -> Result<Reply<String>, MyError> {
+    let r = match db_user::profile(conn, user_rid, offset)? {
+        Ok(x) => x,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    let mut chats = String::new();
+    for i in 0..r.chat_ids.len() {
+        chats += &format!(
+            "<tr><td>{}</td><td class=\"n\">{}</td></tr>\n",
+            link(&format!("/c/{}", r.chat_ids[i]), &r.chat_names[i]),
+            r.messages_by_chat[i],
+        );
+    }
+
+    let replies = |ids: &[String], names: &[String], counts: &[i64]| {
+        let mut out = String::new();
+        for i in 0..ids.len() {
+            out += &format!(
+                "<tr><td>{}</td><td class=\"n\">{}</td></tr>\n",
+                link(&format!("/u/{}", ids[i]), &names[i]),
+                counts[i],
+            );
+        }
+        out
+    };
+
+    let body = fill(PROFILE, &[
+        ("name", &escape(&r.name)),
+        ("first", &date::format_day((r.hours.0 + offset) / 24)),
+        ("last", &date::format_day((r.hours.1 + offset) / 24)),
+        ("streak", &r.longest_streak.1.to_string()),
+        ("chats", &chats),
+        ("hours", &chart::hours_of(&r.name, &r.messages_by_hour)),
+        ("replies_to", &replies(
+            &r.replies_to_ids,
+            &r.replies_to_names,
+            &r.replies_to,
+        )),
+        ("replies_from", &replies(
+            &r.replies_from_ids,
+            &r.replies_from_names,
+            &r.replies_from,
+        )),
+    ]);
+    Ok(Ok(page(&r.name, &body)))
+}
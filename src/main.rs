@@ -1,6 +1,7 @@
 extern crate hyper;
 extern crate rand;
 extern crate futures;
+extern crate libc;
 extern crate rusqlite;
 extern crate serde;
 #[macro_use]
@@ -11,17 +12,32 @@ extern crate telegram_bot_raw;
 extern crate tokio_core;
 extern crate url;
 extern crate reqwest;
+extern crate openssl;
 
 use std::env::args;
 
+mod ava;
+mod badge;
+mod chart;
+mod date;
 mod db;
+mod db_chat_ava;
+mod db_chats;
+mod db_compare;
 mod db_mx;
+mod db_records;
+mod db_retention;
 mod db_util;
 mod process_log;
 mod error;
+mod export;
+mod html;
+mod metrics;
 mod server;
 mod db_tg;
 mod db_tg_ava;
+mod db_user;
+mod db_wrapped;
 use rusqlite::Connection;
 
 fn out(x: Result<(), error::MyError>) {
@@ -40,23 +56,63 @@ fn main() {
         }
         "sync-tg-ava" => {
             let mut conn = Connection::open(&args[2]).unwrap();
-            out(db_tg_ava::update(&mut conn, &args[3]));
+            let opts = match db_tg_ava::Options::parse(&args[4..]) {
+                Ok(x) => x,
+                Err(e) => return eprintln!("{}", e),
+            };
+            out(db_tg_ava::update(&mut conn, &args[3], &opts));
+        }
+        "ava-thumbs" => {
+            out(ava::make_all_thumbs());
+        }
+        "ava-gc" => {
+            out(ava::gc());
+        }
+        "sync-mx-ava" => {
+            let conn = Connection::open(&args[2]).unwrap();
+            out(db_chat_ava::update_mx(&conn, &args[3], &args[4]));
         }
         "sync-mx" => {
             let mut conn = Connection::open(&args[2]).unwrap();
             db_mx::update_from_file(&mut conn, &args[3]);
         }
         "server" => {
-            let mut conn = Connection::open(&args[2]).unwrap();
+            let conn = Connection::open(&args[2]).unwrap();
             server::run(conn);
         }
         "get-chat" => {
-            let mut conn = Connection::open(&args[2]).unwrap();
-            match db::query(&conn, &args[3], None, 0, None, None) {
+            let conn = Connection::open(&args[2]).unwrap();
+            let params = db::Params {
+                dates: None,
+                offset: 0,
+                users: &[],
+                exclude: &[],
+                weekday: None,
+                hours: None,
+                bucket: date::Bucket::Day,
+            };
+            match db::query(&conn, &args[3], &params) {
                 Ok((status, res)) => println!("Status: {}\n{}", status, res),
                 Err(err) => println!("Error:\n{:?}", err),
             }
         }
+        "export" => {
+            let conn = Connection::open(&args[2]).unwrap();
+            let query = args.get(5).map(|x| &**x).unwrap_or("");
+            let params = match server::parse_query(query) {
+                Ok(x) => x,
+                Err(_) => return eprintln!("Invalid parameters"),
+            };
+            let res =
+                export::write_files(&conn, &args[3], &params.get(), &args[4]);
+            match res {
+                Ok(Ok(())) => (),
+                Ok(Err((status, err))) => {
+                    println!("Status: {}\n{}", status, err)
+                }
+                Err(err) => println!("Error:\n{:?}", err),
+            }
+        }
         _ => {
             eprintln!("Invalid arguments");
         }
@@ -0,0 +1,237 @@
+// Summary metrics computed from per-user message counts.
+use std::collections::HashMap;
+
+/// Lower bounds of the message-count histogram buckets.
+pub const HISTOGRAM_FROM: &[i64] =
+    &[1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000];
+
+#[derive(Debug, Serialize)]
+pub struct Concentration {
+    // 0 when everyone posts equally, close to 1 for a monologue
+    pub gini: f64,
+
+    // Share of all messages sent by the top 1, 5 and 10 posters
+    pub top1_share: f64,
+    pub top5_share: f64,
+    pub top10_share: f64,
+
+    pub median_messages: f64,
+
+    // histogram[i]: users with HISTOGRAM_FROM[i] up to HISTOGRAM_FROM[i+1]
+    // messages (exclusive)
+    pub histogram_from: &'static [i64],
+    pub histogram: Vec<i64>,
+}
+
+pub fn concentration(messages_by_user: &[i64]) -> Concentration {
+    let mut counts = messages_by_user.to_vec();
+    counts.sort();
+    let n = counts.len();
+    let total: i64 = counts.iter().sum();
+
+    let gini = if n == 0 || total == 0 {
+        0.0
+    } else {
+        let weighted: i64 = counts
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| (i as i64 + 1) * x)
+            .sum();
+        2.0 * weighted as f64 / (n as f64 * total as f64)
+            - (n as f64 + 1.0) / n as f64
+    };
+
+    let top_share = |k: usize| {
+        if total == 0 {
+            return 0.0;
+        }
+        let top: i64 = counts.iter().rev().take(k).sum();
+        top as f64 / total as f64
+    };
+
+    let median_messages = match n {
+        0 => 0.0,
+        _ if n % 2 == 1 => counts[n / 2] as f64,
+        _ => (counts[n / 2 - 1] + counts[n / 2]) as f64 / 2.0,
+    };
+
+    let mut histogram = vec![0; HISTOGRAM_FROM.len()];
+    for &x in counts.iter() {
+        if let Some(i) = HISTOGRAM_FROM.iter().rposition(|&from| x >= from) {
+            histogram[i] += 1;
+        }
+    }
+
+    Concentration {
+        gini,
+        top1_share: top_share(1),
+        top5_share: top_share(5),
+        top10_share: top_share(10),
+        median_messages,
+        histogram_from: HISTOGRAM_FROM,
+        histogram,
+    }
+}
+
+/// Returns `(first day, length)` of the longest run of consecutive days.
+/// `days` must be sorted and deduplicated.
+pub fn longest_streak(days: &[i64]) -> (i64, i64) {
+    let mut best = (0, 0);
+    let mut start = 0;
+    for (i, &day) in days.iter().enumerate() {
+        if i == 0 || days[i - 1] + 1 != day {
+            start = day;
+        }
+        if day - start + 1 > best.1 {
+            best = (start, day - start + 1);
+        }
+    }
+    best
+}
+
+/// Length of the run of consecutive days ending on `today`, or on the day
+/// before if there is no activity today yet. `days` must be sorted and
+/// deduplicated.
+pub fn current_streak(days: &[i64], today: i64) -> i64 {
+    let mut expected = match days.last() {
+        Some(&last) if last == today || last == today - 1 => last,
+        _ => return 0,
+    };
+    let mut length = 0;
+    for &day in days.iter().rev() {
+        if day != expected {
+            break;
+        }
+        length += 1;
+        expected -= 1;
+    }
+    length
+}
+
+/// Distinct users active over the 7 and 30 days ending on each of `days`,
+/// which must be ascending. `active` holds distinct `(day, user_id)` pairs
+/// sorted by day.
+pub fn rolling_active(active: &[(i64, i64)], days: &[i64]) -> Vec<(i64, i64)> {
+    // Last day each user was seen up to the current one, and how many
+    // users were last seen on each day
+    let mut last_seen = HashMap::new();
+    let mut by_last_seen: HashMap<i64, i64> = HashMap::new();
+    let mut next = 0;
+    let mut result = Vec::with_capacity(days.len());
+    for &day in days.iter() {
+        while next < active.len() && active[next].0 <= day {
+            let (seen, user) = active[next];
+            if let Some(prev) = last_seen.insert(user, seen) {
+                *by_last_seen.entry(prev).or_insert(0) -= 1;
+            }
+            *by_last_seen.entry(seen).or_insert(0) += 1;
+            next += 1;
+        }
+        let within = |n: i64| -> i64 {
+            (day - n + 1..=day)
+                .map(|x| by_last_seen.get(&x).cloned().unwrap_or(0))
+                .sum()
+        };
+        result.push((within(7), within(30)));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn concentration_empty() {
+        let c = concentration(&[]);
+        assert_eq!(c.gini, 0.0);
+        assert_eq!(c.top1_share, 0.0);
+        assert_eq!(c.median_messages, 0.0);
+        assert_eq!(c.histogram, vec![0; HISTOGRAM_FROM.len()]);
+
+        let c = concentration(&[0, 0]);
+        assert_eq!(c.gini, 0.0);
+        assert_eq!(c.top10_share, 0.0);
+    }
+
+    #[test]
+    fn concentration_gini() {
+        assert_eq!(concentration(&[42]).gini, 0.0);
+        assert!(close(concentration(&[5, 5, 5, 5]).gini, 0.0));
+        assert!(close(concentration(&[0, 0, 0, 10]).gini, 0.75));
+        assert!(close(concentration(&[3, 1, 2]).gini, 2.0 / 9.0));
+    }
+
+    #[test]
+    fn concentration_shares() {
+        let counts: Vec<i64> = (1..=20).collect();
+        let c = concentration(&counts);
+        assert!(close(c.top1_share, 20.0 / 210.0));
+        assert!(close(c.top5_share, 90.0 / 210.0));
+        assert!(close(c.top10_share, 155.0 / 210.0));
+        assert!(close(concentration(&[7, 3]).top5_share, 1.0));
+    }
+
+    #[test]
+    fn concentration_median() {
+        assert_eq!(concentration(&[9, 1, 4]).median_messages, 4.0);
+        assert_eq!(concentration(&[9, 1, 4, 2]).median_messages, 3.0);
+        assert_eq!(concentration(&[1, 2]).median_messages, 1.5);
+    }
+
+    #[test]
+    fn concentration_histogram() {
+        let c = concentration(&[0, 1, 1, 4, 5, 99, 100, 10000, 123456]);
+        let mut expected = vec![0; HISTOGRAM_FROM.len()];
+        expected[0] = 2;
+        expected[1] = 1;
+        expected[2] = 1;
+        expected[5] = 1;
+        expected[6] = 1;
+        expected[12] = 2;
+        assert_eq!(c.histogram, expected);
+    }
+
+    #[test]
+    fn longest_streak_runs() {
+        assert_eq!(longest_streak(&[]), (0, 0));
+        assert_eq!(longest_streak(&[5]), (5, 1));
+        assert_eq!(longest_streak(&[1, 2, 3, 7, 8, 10]), (1, 3));
+        assert_eq!(longest_streak(&[1, 2, 5, 6, 7, 8]), (5, 4));
+        // The earliest of equally long runs
+        assert_eq!(longest_streak(&[1, 2, 4, 5, 9]), (1, 2));
+    }
+
+    #[test]
+    fn current_streak_ends() {
+        let days = [1, 5, 6, 7, 8];
+        assert_eq!(current_streak(&days, 8), 4);
+        assert_eq!(current_streak(&days, 9), 4);
+        assert_eq!(current_streak(&days, 10), 0);
+        assert_eq!(current_streak(&[1, 2, 4], 4), 1);
+        assert_eq!(current_streak(&[3], 4), 1);
+        assert_eq!(current_streak(&[], 4), 0);
+    }
+
+    #[test]
+    fn rolling_active_windows() {
+        let active = [(10, 1), (10, 2), (12, 1), (20, 3), (45, 2)];
+        let days = [9, 10, 16, 17, 20, 39, 40, 45];
+        assert_eq!(rolling_active(&active, &days), vec![
+            (0, 0),
+            (2, 2),
+            (2, 2),
+            (1, 2),
+            (1, 3),
+            (0, 3),
+            (0, 2),
+            (1, 2),
+        ]);
+        assert_eq!(rolling_active(&[], &[1, 2]), vec![(0, 0), (0, 0)]);
+        assert_eq!(rolling_active(&active, &[]), vec![]);
+    }
+}
@@ -8,7 +8,7 @@ pub trait LogProcessor {
     fn begin(&mut self) -> Result<Option<u64>, Self::Error>;
     fn commit(&mut self, end_pos: u64) -> Result<(), Self::Error>;
     fn abort(&mut self) -> Result<(), Self::Error>;
-    fn process_line(&mut self, line: &String) -> Result<(), Self::Error>;
+    fn process_line(&mut self, line: &str) -> Result<(), Self::Error>;
 }
 
 // Contract:
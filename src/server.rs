@@ -1,29 +1,259 @@
+use futures::sync::mpsc;
+use futures::{Sink, Stream};
 use hyper::rt::Future;
 use hyper::service::service_fn_ok;
-use hyper::{Body, Response, Server};
+use hyper::{Body, Request, Response, Server};
 use hyper;
 use rusqlite::Connection;
+use std::borrow::Cow;
+use std::io;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::thread;
+use super::ava;
+use super::badge;
+use super::chart;
+use super::date::{self, Bucket};
 use super::db;
+use super::db_chats;
+use super::db_compare;
+use super::db_records;
+use super::db_retention;
+use super::db_user;
+use super::db_util;
+use super::db_wrapped;
+use super::export::{self, Format};
+use super::html;
 
 use url::form_urlencoded;
 
+/// Parameters accepted by all stats-like endpoints.
+pub struct StatsParams {
+    dates: Option<(i64, i64)>,
+    offset: i64,
+    users: Vec<String>,
+    exclude: Vec<String>,
+    weekday: Option<u8>,
+    hours: Option<(u8, u8)>,
+    bucket: Bucket,
+}
+
+impl StatsParams {
+    pub fn get<'a>(&'a self) -> db::Params<'a> {
+        db::Params {
+            dates: self.dates,
+            offset: self.offset,
+            users: &self.users,
+            exclude: &self.exclude,
+            weekday: self.weekday,
+            hours: self.hours,
+            bucket: self.bucket,
+        }
+    }
+}
+
 struct StatsArgs<'a> {
+    chat: &'a str,
+    params: StatsParams,
+    // From the `.csv`/`.ndjson` suffix, or else the `Accept` header
+    format: Option<Format>,
+    table: Option<String>,
+}
+
+struct ChartArgs<'a> {
+    chat: &'a str,
+    kind: chart::Kind,
+    params: StatsParams,
+}
+
+struct BadgeArgs<'a> {
+    chat: &'a str,
+    metric: badge::Metric,
+    params: StatsParams,
+}
+
+struct DashboardArgs<'a> {
+    chat: &'a str,
+    params: StatsParams,
+    query: Option<&'a str>,
+}
+
+struct AvatarArgs<'a> {
+    rnd_id: &'a str,
+    size: Option<u32>,
+}
+
+struct RecordsArgs<'a> {
+    chat: &'a str,
+    offset: i64,
+}
+
+struct RetentionArgs<'a> {
     chat: &'a str,
     dates: Option<(i64, i64)>,
     offset: i64,
-    user: Option<String>,
-    weekday: Option<u8>,
+    weeks: i64,
+}
+
+struct CompareArgs {
+    chats: String,
+    params: StatsParams,
+}
+
+struct UserArgs<'a> {
+    user: &'a str,
+    offset: i64,
+}
+
+struct OverlapArgs<'a> {
+    chat: &'a str,
+    dates: Option<(i64, i64)>,
+    offset: i64,
+    limit: i64,
+}
+
+struct WrappedArgs<'a> {
+    chat: &'a str,
+    user: Option<&'a str>,
+    year: Option<i64>,
+    offset: i64,
+}
+
+struct ChatsArgs {
+    q: String,
+    sort: Option<String>,
+    page: i64,
+    limit: i64,
 }
 
 enum Args<'a> {
     Stats(StatsArgs<'a>),
+    Chart(ChartArgs<'a>),
+    Badge(BadgeArgs<'a>),
+    Avatar(AvatarArgs<'a>),
+    Records(RecordsArgs<'a>),
+    Retention(RetentionArgs<'a>),
+    Compare(CompareArgs),
+    Chats(ChatsArgs),
+    Directory(ChatsArgs),
+    Dashboard(DashboardArgs<'a>),
+    Profile(UserArgs<'a>),
+    Overlap(OverlapArgs<'a>),
+    User(UserArgs<'a>),
+    Wrapped(WrappedArgs<'a>),
     Unknown,
     Invalid,
 }
 
-fn parse_stats<'a>(uri: &'a hyper::Uri) -> Args<'a> {
+/// Resolves the `from`/`to`, `last` and `since` parameters into a range of
+/// days, interpreted in the time zone given by `offset`.
+fn parse_dates(
+    from: Option<String>,
+    to: Option<String>,
+    last: Option<String>,
+    since: Option<String>,
+    offset: i64,
+) -> Result<Option<(i64, i64)>, ()> {
+    let today = date::today(offset);
+    let day = |s: &str, end| date::parse_day(s, today, end).ok_or(());
+    match (from, to, last, since) {
+        (None, None, None, None) => Ok(None),
+        (Some(from), to, None, None) | (None, to, None, Some(from)) => {
+            let to = match to {
+                Some(to) => day(&to, true)?,
+                None => today,
+            };
+            Ok(Some((day(&from, false)?, to)))
+        }
+        (None, None, Some(last), None) => {
+            let from = date::parse_last(&last, today).ok_or(())?;
+            Ok(Some((from, today)))
+        }
+        _ => Err(()),
+    }
+}
+
+fn parse_list(val: &str) -> Vec<String> {
+    val.split(',')
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect()
+}
+
+/// Parses an hours window such as `22-06`.
+fn parse_hours(val: &str) -> Result<(u8, u8), ()> {
+    let mut it = val.splitn(2, '-');
+    let from = it.next().ok_or(())?.parse().map_err(|_| ())?;
+    let to = it.next().ok_or(())?.parse().map_err(|_| ())?;
+    Ok((from, to))
+}
+
+fn parse_params<'a, I>(query: I) -> Result<StatsParams, ()>
+where
+    I: Iterator<Item = (Cow<'a, str>, Cow<'a, str>)>,
+{
+    let mut from: Option<String> = None;
+    let mut to: Option<String> = None;
+    let mut last: Option<String> = None;
+    let mut since: Option<String> = None;
+    let mut offset = None;
+    let mut users = Vec::new();
+    let mut exclude = Vec::new();
+    let mut weekday = None;
+    let mut hours = None;
+    let mut bucket = None;
+    for (key, val) in query {
+        match &*key {
+            "from"    => from    = Some(val.to_string()),
+            "to"      => to      = Some(val.to_string()),
+            "last"    => last    = Some(val.to_string()),
+            "since"   => since   = Some(val.to_string()),
+            "offset"  => offset  = Some(val.parse().map_err(|_| ())?),
+            "user"    => users   = parse_list(&val),
+            "exclude" => exclude = parse_list(&val),
+            "weekday" => weekday = Some(val.parse().map_err(|_| ())?),
+            "hours"   => hours   = Some(parse_hours(&val)?),
+            "bucket"  => bucket  = Some(val.parse()?),
+            _ => return Err(()),
+        }
+    }
+
+    let offset = offset.unwrap_or(0);
+    Ok(StatsParams {
+        dates: parse_dates(from, to, last, since, offset)?,
+        offset,
+        users,
+        exclude,
+        weekday,
+        hours,
+        bucket: bucket.unwrap_or(Bucket::Day),
+    })
+}
+
+/// Like `parse_params`, but only the range is accepted: `from`/`to`, `last`,
+/// `since` and `offset`. Returns the days and the offset.
+fn parse_range<'a, I>(query: I) -> Result<(Option<(i64, i64)>, i64), ()>
+where
+    I: Iterator<Item = (Cow<'a, str>, Cow<'a, str>)>,
+{
+    let mut other = false;
+    let params = parse_params(query.filter(|(key, _)| {
+        let range = ["from", "to", "last", "since", "offset"].contains(&&**key);
+        other |= !range;
+        range
+    }))?;
+    if other {
+        return Err(());
+    }
+    Ok((params.dates, params.offset))
+}
+
+/// Parses stats parameters from a query string, for the command line.
+pub fn parse_query(query: &str) -> Result<StatsParams, ()> {
+    parse_params(form_urlencoded::parse(query.as_bytes()))
+}
+
+fn parse_args<'a>(uri: &'a hyper::Uri) -> Args<'a> {
     macro_rules! try2 {
         ($e:expr) => {
             match $e {
@@ -39,38 +269,428 @@ fn parse_stats<'a>(uri: &'a hyper::Uri) -> Args<'a> {
     let segments : Vec<&'a str> = uri.path()[1..].split('/').collect();
 
     if segments.len() == 2 && segments[0] == "stats" {
-        let mut from = None;
-        let mut to = None;
+        let (chat, format) = if segments[1].ends_with(".csv") {
+            (&segments[1][..segments[1].len() - 4], Some(Format::Csv))
+        } else if segments[1].ends_with(".ndjson") {
+            (&segments[1][..segments[1].len() - 7], Some(Format::Ndjson))
+        } else {
+            (segments[1], None)
+        };
+
+        let mut table = None;
+        let params = try2!(parse_params(query.filter(|(key, val)| {
+            if key != "table" {
+                return true;
+            }
+            table = Some(val.to_string());
+            false
+        })));
+
+        return Args::Stats(StatsArgs {
+            chat,
+            params,
+            format,
+            table,
+        });
+    }
+
+    if segments.len() == 3 && segments[0] == "chart" {
+        return match chart::Kind::from_file(segments[2]) {
+            Some(kind) => Args::Chart(ChartArgs {
+                chat: segments[1],
+                kind,
+                params: try2!(parse_params(query)),
+            }),
+            None => Args::Unknown,
+        };
+    }
+
+    if segments.len() == 3 && segments[0] == "badge" {
+        let metric = match badge::Metric::from_file(segments[2]) {
+            Some(x) => x,
+            None => return Args::Unknown,
+        };
+        let mut params = try2!(parse_params(query));
+        if params.dates.is_none() {
+            let today = date::today(params.offset);
+            params.dates = Some((today - badge::DEFAULT_DAYS + 1, today));
+        }
+
+        return Args::Badge(BadgeArgs {
+            chat: segments[1],
+            metric,
+            params,
+        });
+    }
+
+    if segments.len() == 2 && segments[0] == "ava"
+        && segments[1].ends_with(".jpg")
+    {
+        let mut size = None;
+        for (key, val) in query {
+            match &*key {
+                "size" => size = Some(try2!(val.parse())),
+                _ => return Args::Invalid,
+            }
+        }
+        if size.is_some_and(|x| !ava::SIZES.contains(&x)) {
+            return Args::Invalid;
+        }
+
+        return Args::Avatar(AvatarArgs {
+            rnd_id: &segments[1][..segments[1].len() - 4],
+            size,
+        });
+    }
+
+    if segments.len() == 3 && segments[0] == "stats"
+        && segments[2] == "records"
+    {
+        let mut offset = None;
+        for (key, val) in query {
+            match &*key {
+                "offset" => offset = Some(try2!(val.parse())),
+                _ => return Args::Invalid,
+            }
+        }
+
+        return Args::Records(RecordsArgs {
+            chat: segments[1],
+            offset: offset.unwrap_or(0),
+        });
+    }
+
+    if segments.len() == 3 && segments[0] == "stats"
+        && segments[2] == "retention"
+    {
+        let mut weeks = None;
+        let (dates, offset) = try2!(parse_range(query.filter(|(key, val)| {
+            if key != "weeks" {
+                return true;
+            }
+            weeks = Some(val.to_string());
+            false
+        })));
+
+        return Args::Retention(RetentionArgs {
+            chat: segments[1],
+            dates,
+            offset,
+            weeks: try2!(weeks.map_or(Ok(12), |x| x.parse())),
+        });
+    }
+
+    if segments.len() == 1 && segments[0] == "compare" {
+        let mut chats = None;
+        let params = try2!(parse_params(query.filter(|(key, val)| {
+            if key != "chats" {
+                return true;
+            }
+            chats = Some(val.to_string());
+            false
+        })));
+
+        return Args::Compare(CompareArgs {
+            chats: try2!(chats.ok_or(())),
+            params,
+        });
+    }
+
+    if segments.len() == 1
+        && (segments[0] == "chats" || segments[0].is_empty())
+    {
+        let mut q = None;
+        let mut sort = None;
+        let mut page = None;
+        let mut limit = None;
+        for (key, val) in query {
+            match &*key {
+                "q"     => q     = Some(val.to_string()),
+                "sort"  => sort  = Some(val.to_string()),
+                "page"  => page  = Some(try2!(val.parse())),
+                "limit" => limit = Some(try2!(val.parse())),
+                _ => return Args::Invalid,
+            }
+        }
+
+        if segments[0].is_empty() {
+            return Args::Directory(ChatsArgs {
+                q: q.unwrap_or_default(),
+                sort,
+                page: page.unwrap_or(0),
+                limit: limit.unwrap_or(html::PAGE_SIZE),
+            });
+        }
+
+        return Args::Chats(ChatsArgs {
+            q: q.unwrap_or_default(),
+            sort,
+            page: page.unwrap_or(0),
+            limit: limit.unwrap_or(db_chats::MAX_LIMIT),
+        });
+    }
+
+    if segments.len() == 2 && segments[0] == "c" {
+        return Args::Dashboard(DashboardArgs {
+            chat: segments[1],
+            params: try2!(parse_params(query)),
+            query: uri.query(),
+        });
+    }
+
+    if segments.len() == 3 && segments[0] == "chats"
+        && segments[2] == "overlap"
+    {
+        let mut limit = None;
+        let (dates, offset) = try2!(parse_range(query.filter(|(key, val)| {
+            if key != "limit" {
+                return true;
+            }
+            limit = Some(val.to_string());
+            false
+        })));
+
+        return Args::Overlap(OverlapArgs {
+            chat: segments[1],
+            dates,
+            offset,
+            limit: try2!(limit.map_or(Ok(20), |x| x.parse())),
+        });
+    }
+
+    if segments.len() == 2 && (segments[0] == "user" || segments[0] == "u") {
         let mut offset = None;
-        let mut user: Option<String> = None;
-        let mut weekday = None;
         for (key, val) in query {
             match &*key {
-                "from"    => from    = Some(try2!(val.parse())),
-                "to"      => to      = Some(try2!(val.parse())),
-                "offset"  => offset  = Some(try2!(val.parse())),
-                "user"    => user    = Some(val.to_owned().to_string()),
-                "weekday" => weekday = Some(try2!(val.parse())),
+                "offset" => offset = Some(try2!(val.parse())),
                 _ => return Args::Invalid,
             }
         }
 
-        let dates = match (from, to) {
-            (Some(from), Some(to)) => Some((from, to)),
-            (None, None) => None,
-            _ => return Args::Invalid,
+        let args = UserArgs {
+            user: segments[1],
+            offset: offset.unwrap_or(0),
         };
+        return if segments[0] == "u" {
+            Args::Profile(args)
+        } else {
+            Args::User(args)
+        };
+    }
 
-        return Args::Stats(StatsArgs {
+    if (segments.len() == 2 || segments.len() == 3)
+        && segments[0] == "wrapped"
+    {
+        let mut year = None;
+        let mut offset = None;
+        for (key, val) in query {
+            match &*key {
+                "year"   => year   = Some(try2!(val.parse())),
+                "offset" => offset = Some(try2!(val.parse())),
+                _ => return Args::Invalid,
+            }
+        }
+
+        return Args::Wrapped(WrappedArgs {
             chat: segments[1],
-            dates: dates,
+            user: segments.get(2).copied(),
+            year,
             offset: offset.unwrap_or(0),
-            user: user,
-            weekday: weekday,
         });
     }
 
-    return Args::Unknown;
+    Args::Unknown
+}
+
+/// Picks an export format from the `Accept` header, if it asks for one.
+fn accept_format(req: &Request<Body>) -> Option<Format> {
+    let accept = req.headers().get("Accept")?.to_str().ok()?;
+    if accept.contains("text/csv") {
+        Some(Format::Csv)
+    } else if accept.contains("application/x-ndjson") {
+        Some(Format::Ndjson)
+    } else {
+        None
+    }
+}
+
+fn handle(conn: &Connection, req: &Request<Body>) -> Response<Body> {
+    let mut res = Response::builder();
+    res.header("Access-Control-Allow-Origin", "*");
+
+    let (status, text) = match parse_args(req.uri()) {
+        Args::Stats(x) => match x.format.or_else(|| accept_format(req)) {
+            None => db::query_http(conn, x.chat, &x.params.get()),
+            Some(format) => {
+                let table = x.table.as_deref();
+                let p = x.params.get();
+                match export::query(conn, x.chat, &p, format, table) {
+                    Ok(Ok(text)) => {
+                        res.header("Content-Type", format.content_type());
+                        (200, text)
+                    }
+                    Ok(Err((status, err))) => (status, String::from(err)),
+                    Err(e) => db_util::http(Err(e)),
+                }
+            }
+        },
+        Args::Chart(x) => {
+            let (status, text) =
+                chart::query_http(conn, x.chat, &x.params.get(), x.kind);
+            if status == 200 {
+                res.header("Content-Type", chart::CONTENT_TYPE);
+            }
+            (status, text)
+        }
+        Args::Badge(x) => {
+            let (status, text) =
+                badge::query_http(conn, x.chat, &x.params.get(), x.metric);
+            if status == 200 {
+                res.header("Content-Type", chart::CONTENT_TYPE);
+                res.header("Cache-Control", badge::CACHE_CONTROL);
+            }
+            (status, text)
+        }
+        Args::Avatar(x) => {
+            let if_none_match = req.headers()
+                .get("If-None-Match")
+                .and_then(|x| x.to_str().ok());
+            let webp = req.headers()
+                .get("Accept")
+                .and_then(|x| x.to_str().ok())
+                .is_some_and(|x| x.contains("image/webp"));
+            match ava::get(conn, x.rnd_id, x.size, webp, if_none_match) {
+                Ok(Ok(ava)) => {
+                    res.header("Content-Type", ava.content_type)
+                        .header("Vary", "Accept")
+                        .header("ETag", ava.etag.as_str())
+                        .header("Cache-Control", ava.cache_control);
+                    return match ava.body {
+                        Some(body) => res.status(200).body(Body::from(body)),
+                        None => res.status(304).body(Body::empty()),
+                    }.unwrap();
+                }
+                Ok(Err((status, err))) => (status, String::from(err)),
+                Err(e) => db_util::http(Err(e)),
+            }
+        }
+        Args::Records(x) => db_records::query_http(conn, x.chat, x.offset),
+        Args::Retention(x) => db_retention::query_http(
+            conn,
+            x.chat,
+            x.dates,
+            x.offset,
+            x.weeks,
+        ),
+        Args::Compare(x) => {
+            let chats: Vec<&str> = x.chats.split(',').collect();
+            db_compare::query_http(conn, &chats, &x.params.get())
+        }
+        Args::Chats(x) => db_chats::query_http(
+            conn,
+            &x.q,
+            x.sort.as_deref(),
+            x.page,
+            x.limit,
+        ),
+        Args::Overlap(x) => db_chats::overlap_http(
+            conn,
+            x.chat,
+            x.dates,
+            x.offset,
+            x.limit,
+        ),
+        Args::Directory(x) => {
+            res.header("Content-Type", html::CONTENT_TYPE);
+            html::directory_http(
+                conn,
+                &x.q,
+                x.sort.as_deref(),
+                x.page,
+                x.limit,
+            )
+        }
+        Args::Dashboard(x) => {
+            res.header("Content-Type", html::CONTENT_TYPE);
+            html::dashboard_http(conn, x.chat, &x.params.get(), x.query)
+        }
+        Args::Profile(x) => {
+            res.header("Content-Type", html::CONTENT_TYPE);
+            html::profile_http(conn, x.user, x.offset)
+        }
+        Args::User(x) => db_user::query_http(conn, x.user, x.offset),
+        Args::Wrapped(x) => db_wrapped::query_http(
+            conn,
+            x.chat,
+            x.user,
+            x.year,
+            x.offset,
+        ),
+        Args::Unknown => (404, String::from("404")),
+        Args::Invalid => (400, String::from("400")),
+    };
+
+    res.status(status).body(Body::from(text)).unwrap()
+}
+
+/// Serves NDJSON exports as they are rendered, on a thread of their own
+/// that only takes `conn` for one piece at a time. None for other requests.
+fn stream(
+    conn: &Arc<Mutex<Connection>>,
+    req: &Request<Body>,
+) -> Option<Response<Body>> {
+    let x = match parse_args(req.uri()) {
+        Args::Stats(x) => x,
+        _ => return None,
+    };
+    if x.format.or_else(|| accept_format(req)) != Some(Format::Ndjson) {
+        return None;
+    }
+
+    let mut res = Response::builder();
+    res.header("Access-Control-Allow-Origin", "*");
+    let p = x.params.get();
+    let dates = match export::ndjson_range(&conn.lock().unwrap(), x.chat, &p) {
+        Ok(Ok(x)) => x,
+        Ok(Err((status, err))) => {
+            return Some(res.status(status).body(Body::from(err)).unwrap())
+        }
+        Err(e) => {
+            let (status, text) = db_util::http(Err(e));
+            return Some(res.status(status).body(Body::from(text)).unwrap());
+        }
+    };
+
+    let (tx, rx) = mpsc::channel(1);
+    let conn = conn.clone();
+    let chat = String::from(x.chat);
+    let params = x.params;
+    thread::spawn(move || {
+        let mut tx = Some(tx);
+        let res = export::ndjson_stream(
+            || conn.lock().unwrap(),
+            &chat,
+            &params.get(),
+            dates,
+            |text| match tx.take().unwrap().send(text).wait() {
+                Ok(x) => {
+                    tx = Some(x);
+                    true
+                }
+                // The client went away
+                Err(_) => false,
+            },
+        );
+        match res {
+            Ok(Ok(())) => (),
+            Ok(Err((_, err))) => eprintln!("Export of {}: {}", chat, err),
+            Err(e) => eprintln!("Export of {}: {:?}", chat, e),
+        }
+    });
+
+    let body = rx.map_err(|()| io::Error::other(""));
+    res.header("Content-Type", Format::Ndjson.content_type());
+    Some(res.status(200).body(Body::wrap_stream(body)).unwrap())
 }
 
 pub fn run(conn: Connection) {
@@ -80,33 +700,11 @@ pub fn run(conn: Connection) {
     let new_svc = move || {
         let conn = conn.clone();
         service_fn_ok(move |req| {
+            if let Some(res) = stream(&conn, &req) {
+                return res;
+            }
             let conn = conn.lock().unwrap();
-            match parse_stats(req.uri()) {
-                Args::Stats(x) => {
-                    let (status, text) = db::query_http(
-                        &conn,
-                        x.chat,
-                        x.dates,
-                        x.offset,
-                        x.user.as_ref().map(|x| &**x),
-                        x.weekday,
-                    );
-                    Response::builder()
-                        .header("Access-Control-Allow-Origin", "*")
-                        .status(status)
-                        .body(Body::from(text))
-                }
-                Args::Unknown => 
-                    Response::builder()
-                        .header("Access-Control-Allow-Origin", "*")
-                        .status(404)
-                        .body(Body::from("404")),
-                Args::Invalid => 
-                    Response::builder()
-                        .header("Access-Control-Allow-Origin", "*")
-                        .status(400)
-                        .body(Body::from("400")),
-            }.unwrap()
+            handle(&conn, &req)
         })
     };
 